@@ -0,0 +1,184 @@
+use crate::{
+    Config, Errors,
+    command_builder::{Execute as _, Executer, quote},
+    diff, git,
+    history::short_hash,
+    lock::{self, LockChange},
+    nix::NixInfo,
+    options::ToSwitch,
+};
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+
+/// The options for bisecting 'flake.lock' changes.
+pub struct Bisect {
+    /// The lock file the target is known to work with.
+    pub good: Box<str>,
+    /// The lock file the target is known to be broken with.
+    ///
+    /// Defaults to the current 'flake.lock' file.
+    pub bad: Option<Box<str>>,
+    /// The target to build at each step.
+    pub target: ToSwitch,
+    /// A shell command deciding whether each build is good, by exiting successfully.
+    ///
+    /// The user is asked instead if there is no command.
+    pub command: Option<Box<str>>,
+}
+
+/// Reads a lock file from a reference to it.
+///
+/// A reference is either "git:<rev>", for the 'flake.lock' file at a revision of the nix
+/// configuration, or a path to a lock file.
+fn read_lock(config: &Config, reference: &str) -> Result<Value, Errors> {
+    let error = || Errors::LockRead {
+        reference: reference.into(),
+    };
+
+    let text = match reference.strip_prefix("git:") {
+        Some(rev) => git::show_file(config.nix_path.as_std_path(), rev, "flake.lock"),
+        None => std::fs::read_to_string(reference).ok(),
+    }
+    .ok_or_else(error)?;
+
+    serde_json::from_str(&text).map_err(|_| error())
+}
+
+/// Finds the input update that broke the target, by bisecting the inputs changed between the
+/// good & bad lock files.
+///
+/// Each step builds the target with the good lock file plus some of the changed inputs, then
+/// decides whether the build is good. A build that fails is bad.
+pub fn bisect<T: std::io::Write>(
+    config: &Config,
+    options: &Bisect,
+    nix: &NixInfo,
+    executer: &mut Executer<T>,
+) -> Result<LockChange, Errors> {
+    let good = read_lock(config, &options.good)?;
+    let bad = match &options.bad {
+        Some(reference) => read_lock(config, reference)?,
+        None => read_lock(config, config.nix_path.join("flake.lock").as_str())?,
+    };
+
+    let changes = lock::changes(&good, &bad);
+    let lock_path = TempFile(
+        std::env::temp_dir().join(format!("system-manager-bisect-{}.lock", std::process::id())),
+    );
+
+    let total = changes.len();
+    search(&changes, |count, remaining| {
+        executer.report(&format!(
+            "Testing with {count} of {total} input updates ({remaining} remaining to bisect)."
+        ))?;
+
+        let applied: Vec<_> = changes[..count].iter().collect();
+        let lock = lock::apply(&good, &bad, &applied);
+        std::fs::write(&lock_path.0, serde_json::to_string_pretty(&lock)?).map_err(|_| {
+            Errors::DataWrite {
+                path: lock_path.0.clone().into_boxed_path(),
+            }
+        })?;
+
+        is_good(config, options, &lock_path.0, nix, executer)
+    })
+    .cloned()
+}
+
+/// A temporary file, removed once dropped.
+struct TempFile(PathBuf);
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Finds the first change that breaks the target, by binary search.
+///
+/// The test is given how many of the changes to apply, & how many steps remain, returning
+/// whether the target is good with them. No changes are assumed good & every change bad.
+pub(crate) fn search<C>(
+    changes: &[C],
+    mut test: impl FnMut(usize, usize) -> Result<bool, Errors>,
+) -> Result<&C, Errors> {
+    if changes.is_empty() {
+        return Err(Errors::NoLockChanges);
+    }
+
+    // The first `good_count` changes are known to be good & the first `bad_count` to be bad.
+    let (mut good_count, mut bad_count) = (0, changes.len());
+    while bad_count - good_count > 1 {
+        let count = (good_count + bad_count) / 2;
+        if test(count, bad_count - good_count - 1)? {
+            good_count = count;
+        } else {
+            bad_count = count;
+        }
+    }
+
+    Ok(&changes[bad_count - 1])
+}
+
+/// Builds the target with the given lock file & decides whether the build is good.
+fn is_good<T: std::io::Write>(
+    config: &Config,
+    options: &Bisect,
+    lock_path: &Path,
+    nix: &NixInfo,
+    executer: &mut Executer<T>,
+) -> Result<bool, Errors> {
+    let lock_args = format!(
+        " --reference-lock-file {} --no-write-lock-file",
+        quote(&lock_path.to_string_lossy())
+    );
+
+    let out_path = match diff::build(config, &options.target, &lock_args, nix, executer) {
+        Ok(Some(out_path)) => out_path,
+        Ok(None) => {
+            return Err(Errors::UnbuildableTarget {
+                name: options.target.name().into(),
+            });
+        }
+        Err(
+            Errors::CommandError(_)
+            | Errors::EvaluationFailed { .. }
+            | Errors::BuildFailed { .. }
+            | Errors::DownloadFailed { .. }
+            | Errors::MissingAttribute { .. }
+            | Errors::GitTreeNotClean { .. },
+        ) => {
+            executer.report("Build failed, marking as bad.")?;
+            return Ok(false);
+        }
+        Err(err) => return Err(err),
+    };
+
+    match &options.command {
+        Some(command) => Ok(executer
+            .execute(&format!(
+                "SYSTEM_MANAGER_RESULT={} sh -c {}",
+                quote(&out_path),
+                quote(command)
+            ))
+            .is_ok()),
+        None => Ok(executer.ask(&format!("Is the build at '{out_path}' good?"))?),
+    }
+}
+
+/// Describes the input update found by bisecting.
+pub fn describe(change: &LockChange) -> String {
+    let rev = |rev: &Option<Box<str>>| {
+        rev.as_deref()
+            .map(short_hash)
+            .unwrap_or("nothing")
+            .to_owned()
+    };
+
+    format!(
+        "The '{}' input update from {} to {} broke the build.",
+        change.name,
+        rev(&change.old_rev),
+        rev(&change.new_rev)
+    )
+}
@@ -0,0 +1,136 @@
+use crate::{Config, Errors, generation, git, options::ToSwitch};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, path::PathBuf};
+
+/// The name of the file caching what previous switches built, in the data directory.
+pub const CACHE_FILE: &str = "switch-cache.json";
+
+/// Identifies the inputs of a switch, so a switch with the same inputs can be skipped.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Fingerprint {
+    /// The git tree hash of the nix configuration, including uncommitted changes.
+    pub tree: Box<str>,
+    /// The git hash of the 'flake.lock' file.
+    pub lock: Option<Box<str>>,
+    /// The command that switches the target.
+    pub command: Box<str>,
+}
+
+impl Fingerprint {
+    /// Fingerprints the nix configuration & the command switching a target with it.
+    ///
+    /// Returns [`None`] if the nix configuration isn't in a git repository.
+    pub fn new(config: &Config, command: &str) -> Option<Self> {
+        let flake = config.nix_path.as_std_path();
+        Some(Self {
+            tree: git::worktree_hash(flake)?.into(),
+            lock: git::hash_file(flake, "flake.lock").map(Into::into),
+            command: command.into(),
+        })
+    }
+}
+
+/// What a target was last switched to.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Entry {
+    fingerprint: Fingerprint,
+    store_path: Box<str>,
+}
+
+/// The cache of what previous switches built, per identity & target.
+#[derive(Debug)]
+pub struct SwitchCache {
+    path: PathBuf,
+    entries: BTreeMap<Box<str>, Entry>,
+    /// Looks up the store path of the active generation of a target.
+    active: fn(&ToSwitch) -> Option<Box<str>>,
+}
+
+impl SwitchCache {
+    /// Reads the cache stored at the given path.
+    ///
+    /// A missing or unreadable cache is treated as empty.
+    pub fn open(path: impl Into<PathBuf>) -> Self {
+        Self::open_with(path, active_store_path)
+    }
+
+    /// Reads the cache stored at the given path, looking up the active generations of targets
+    /// with the given function.
+    pub(crate) fn open_with(
+        path: impl Into<PathBuf>,
+        active: fn(&ToSwitch) -> Option<Box<str>>,
+    ) -> Self {
+        let path = path.into();
+        let entries = std::fs::read_to_string(&path)
+            .ok()
+            .and_then(|text| serde_json::from_str(&text).ok())
+            .unwrap_or_default();
+
+        Self {
+            path,
+            entries,
+            active,
+        }
+    }
+
+    fn key(config: &Config, target: &ToSwitch) -> Box<str> {
+        format!("{}/{}", config.identity, target.name()).into()
+    }
+
+    /// Whether the target was last switched with the same fingerprint & is still active.
+    pub fn is_up_to_date(
+        &self,
+        config: &Config,
+        target: &ToSwitch,
+        fingerprint: &Fingerprint,
+    ) -> bool {
+        self.entries
+            .get(&Self::key(config, target))
+            .is_some_and(|entry| {
+                entry.fingerprint == *fingerprint
+                    && (self.active)(target).as_deref() == Some(&*entry.store_path)
+            })
+    }
+
+    /// Records that the target was switched with the fingerprint, along with its active store path.
+    pub fn record(
+        &mut self,
+        config: &Config,
+        target: &ToSwitch,
+        fingerprint: Fingerprint,
+    ) -> Result<(), Errors> {
+        let Some(store_path) = (self.active)(target) else {
+            return Ok(());
+        };
+
+        self.entries.insert(
+            Self::key(config, target),
+            Entry {
+                fingerprint,
+                store_path,
+            },
+        );
+
+        std::fs::write(&self.path, serde_json::to_string_pretty(&self.entries)?).map_err(|_| {
+            Errors::DataWrite {
+                path: self.path.clone().into_boxed_path(),
+            }
+        })
+    }
+}
+
+/// The store path of the active generation of the target, if it has one.
+///
+/// Custom targets have no generation, so they're never up to date.
+pub fn active_store_path(target: &ToSwitch) -> Option<Box<str>> {
+    let profile = match target {
+        ToSwitch::System { .. } => PathBuf::from("/run/current-system"),
+        ToSwitch::Home => generation::home_profile()?,
+        ToSwitch::Custom { .. } => return None,
+    };
+
+    std::fs::canonicalize(profile)
+        .ok()?
+        .to_str()
+        .map(Into::into)
+}
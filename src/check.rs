@@ -0,0 +1,252 @@
+use crate::{
+    Config, Errors,
+    command_builder::{Executer, quote},
+    diff,
+    nix::NixInfo,
+    nix_log,
+    options::ToSwitch,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::Write as _,
+    time::{Duration, Instant},
+};
+
+/// The format of the report of checking every configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    Json,
+    Junit,
+}
+
+/// The configurations defined in a flake, by identity.
+#[derive(Deserialize, Debug, Default, PartialEq, Eq)]
+pub struct Configurations {
+    pub nixos: Vec<Box<str>>,
+    pub home: Vec<Box<str>>,
+}
+
+impl Configurations {
+    /// Parses the output of the expression listing the configurations.
+    pub(crate) fn parse(text: &str) -> Option<Self> {
+        serde_json::from_str(text).ok()
+    }
+}
+
+/// The result of a single check.
+#[derive(Serialize, Debug, Clone)]
+pub struct Check {
+    /// The flake output the check belongs to, such as "nixosConfigurations".
+    pub category: &'static str,
+    /// The identity that was built, or the name of the check.
+    pub name: Box<str>,
+    pub passed: bool,
+    /// Why the check failed.
+    pub error: Option<Box<str>>,
+    /// The last lines of the log of the failed check.
+    pub log: Box<[Box<str>]>,
+    pub seconds: f64,
+}
+
+impl Check {
+    pub(crate) fn new(
+        category: &'static str,
+        name: &str,
+        result: Result<(), Errors>,
+        log: &[Box<str>],
+        duration: Duration,
+    ) -> Self {
+        Self {
+            category,
+            name: name.into(),
+            passed: result.is_ok(),
+            log: if result.is_ok() {
+                Box::new([])
+            } else {
+                // Nix's colours aren't valid in XML.
+                log.iter()
+                    .map(|line| nix_log::strip_escapes(line).into())
+                    .collect()
+            },
+            error: result.err().map(|err| err.to_string().into()),
+            seconds: duration.as_secs_f64(),
+        }
+    }
+}
+
+/// The results of checking every configuration in a flake.
+#[derive(Serialize, Debug, Default)]
+pub struct Report {
+    pub checks: Vec<Check>,
+}
+
+impl Report {
+    /// Formats the report in the given machine-readable format.
+    pub fn format(&self, format: Format) -> Result<String, Errors> {
+        match format {
+            Format::Json => Ok(serde_json::to_string_pretty(self)? + "\n"),
+            Format::Junit => Ok(self.junit()),
+        }
+    }
+
+    /// Formats the report as a JUnit XML test suite.
+    fn junit(&self) -> String {
+        let failures = self.checks.iter().filter(|check| !check.passed).count();
+        let time: f64 = self.checks.iter().map(|check| check.seconds).sum();
+
+        let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        let _ = writeln!(
+            xml,
+            "<testsuite name=\"system-manager check-all\" tests=\"{}\" failures=\"{failures}\" time=\"{time:.3}\">",
+            self.checks.len()
+        );
+        for check in &self.checks {
+            let _ = write!(
+                xml,
+                "  <testcase classname=\"{}\" name=\"{}\" time=\"{:.3}\"",
+                check.category,
+                escape(&check.name),
+                check.seconds
+            );
+            match &check.error {
+                Some(error) if check.log.is_empty() => {
+                    let _ = writeln!(
+                        xml,
+                        ">\n    <failure message=\"{}\"/>\n  </testcase>",
+                        escape(error)
+                    );
+                }
+                Some(error) => {
+                    let _ = writeln!(
+                        xml,
+                        ">\n    <failure message=\"{}\">{}</failure>\n  </testcase>",
+                        escape(error),
+                        escape(&check.log.join("\n"))
+                    );
+                }
+                None => xml.push_str("/>\n"),
+            }
+        }
+        xml.push_str("</testsuite>\n");
+
+        xml
+    }
+
+    /// Converts the report into an error naming the failed checks, if any.
+    pub fn into_result(self) -> Result<(), Errors> {
+        let failed: Vec<_> = self
+            .checks
+            .iter()
+            .filter(|check| !check.passed)
+            .map(|check| format!("{}.{}", check.category, check.name))
+            .collect();
+
+        if failed.is_empty() {
+            return Ok(());
+        }
+        Err(Errors::ChecksFailed {
+            checks: failed.join(", ").into(),
+        })
+    }
+}
+
+/// Escapes the text for use in XML attributes & elements, dropping control characters XML can't
+/// hold.
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace(
+            |c: char| c.is_control() && !matches!(c, '\n' | '\t' | '\r'),
+            "",
+        )
+}
+
+/// Lists the system & home-manager configurations defined in the flake.
+fn configurations<T: std::io::Write>(
+    config: &Config,
+    nix: &NixInfo,
+    executer: &mut Executer<T>,
+) -> Result<Configurations, Errors> {
+    // A JSON string is also a valid nix string, as long as it has no interpolation.
+    let flake = serde_json::to_string(config.nix_path.as_str())?.replace("${", "\\${");
+    let expression = format!(
+        "let flake = builtins.getFlake {flake}; names = outputs: builtins.attrNames (flake.${{outputs}} or {{ }}); in {{ nixos = names \"nixosConfigurations\"; home = names \"homeConfigurations\"; }}"
+    );
+    let output = match executer.capture(&format!(
+        "nix{} eval --json --impure --expr {}",
+        nix.nix_args(),
+        quote(&expression)
+    )) {
+        Ok(output) => output,
+        Err(err) => {
+            // Any kept errors are only reported for the checks.
+            let log = executer.log().join("\n");
+            executer.report(&log)?;
+            return Err(err.into());
+        }
+    };
+
+    Configurations::parse(&output).ok_or(Errors::UnlistedConfigurations)
+}
+
+/// Builds every system & home-manager configuration in the flake without activating them,
+/// then runs "nix flake check".
+///
+/// Nothing is run with sudo, so this is suitable for CI. Each failed check keeps the end of its
+/// log, if the executer kept it.
+pub fn check_all<T: std::io::Write>(
+    config: &Config,
+    nix: &NixInfo,
+    executer: &mut Executer<T>,
+) -> Result<Report, Errors> {
+    let configurations = configurations(config, nix, executer)?;
+    let mut report = Report::default();
+
+    let targets = configurations
+        .nixos
+        .iter()
+        .map(|identity| {
+            (
+                "nixosConfigurations",
+                identity,
+                ToSwitch::System { offline: false },
+            )
+        })
+        .chain(
+            configurations
+                .home
+                .iter()
+                .map(|identity| ("homeConfigurations", identity, ToSwitch::Home)),
+        );
+    for (category, identity, target) in targets {
+        let config = Config {
+            identity: identity.clone(),
+            ..config.clone()
+        };
+        let start = Instant::now();
+        let result = diff::build(&config, &target, "", nix, executer).map(|_| ());
+        let log = nix_log::tail(executer.log(), config.build_log_lines);
+        report
+            .checks
+            .push(Check::new(category, identity, result, log, start.elapsed()));
+    }
+
+    let start = Instant::now();
+    let result = executer
+        .capture_nix(&format!(
+            "nix{} flake check {}{}",
+            nix.nix_args(),
+            quote(config.nix_path.as_str()),
+            executer.log_args(nix)
+        ))
+        .map(|_| ())
+        .map_err(|err| nix_log::failure(err, executer.log(), &config.identity));
+    let log = nix_log::tail(executer.log(), config.build_log_lines);
+    report
+        .checks
+        .push(Check::new("flake", "check", result, log, start.elapsed()));
+
+    Ok(report)
+}
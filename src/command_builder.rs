@@ -1,18 +1,46 @@
+use crate::{nix::NixInfo, nix_log::LogState};
 use std::{
-    io::{Stdout, Write},
+    borrow::Cow,
+    io::{BufRead as _, BufReader, ErrorKind, Read as _, Stdout, Write},
+    path::PathBuf,
     process::{Command, Stdio},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc,
+    },
+    time::Duration,
 };
 
+/// The name of the file nix writes a copy of its log to, in the log directory of an executer.
+const LOG_FILE: &str = "log.json";
+
 /// Used to execute commands.
 ///
 /// It allows for shared output and configurations between commands.
 pub struct Executer<Out: Write> {
     /// Whether the commands should be executed or displayed.
     display: bool,
+    /// Whether to render the progress of nix commands from their internal-json log.
+    progress: bool,
+    /// The shell command the log of nix commands is piped to, such as nix-output-monitor.
+    monitor: Option<Box<str>>,
+    /// Whether the errors of captured commands & the log of nix commands are only kept, instead
+    /// of being shown.
+    quiet: bool,
+    /// The messages & build output of the last nix command executed.
+    log: Vec<Box<str>>,
+    /// The directory nix commands write a copy of their log to, when it isn't followed as it's
+    /// written.
+    log_directory: PathBuf,
     /// Where to write the commands to.
     out: Out,
 }
 
+/// Whether the answer to a yes or no question is a yes.
+fn is_yes(answer: &str) -> bool {
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
 /// The possible errors when executing commands.
 #[derive(thiserror::Error, Debug)]
 pub enum CommandError {
@@ -30,7 +58,343 @@ pub enum CommandError {
 impl<Out: Write> Executer<Out> {
     /// Creates a new [`Executer<Out>`].
     pub fn new(display: bool, out: Out) -> Self {
-        Self { display, out }
+        // Each executer has its own log directory, even across processes.
+        static EXECUTERS: AtomicUsize = AtomicUsize::new(0);
+        let log_directory = std::env::temp_dir().join(format!(
+            "system-manager-log-{}-{}",
+            std::process::id(),
+            EXECUTERS.fetch_add(1, Ordering::Relaxed)
+        ));
+
+        Self {
+            display,
+            progress: false,
+            monitor: None,
+            quiet: false,
+            log: Vec::new(),
+            log_directory,
+            out,
+        }
+    }
+
+    /// Sets whether the progress of nix commands is rendered from their internal-json log,
+    /// instead of passing their output through.
+    pub fn with_progress(mut self, progress: bool) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    /// Sets whether the log of nix commands is piped to nix-output-monitor, when it's installed.
+    ///
+    /// This takes precedence over rendering the progress.
+    pub fn with_nom(mut self, nom: bool) -> Self {
+        let installed = || {
+            Command::new("nom")
+                .arg("--version")
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status()
+                .is_ok_and(|status| status.success())
+        };
+        if nom && installed() {
+            self = self.with_monitor("nom --json");
+        }
+        self
+    }
+
+    /// Sets the shell command the internal-json log of nix commands is piped to.
+    pub(crate) fn with_monitor(mut self, monitor: &str) -> Self {
+        self.monitor = Some(monitor.into());
+        self
+    }
+
+    /// Whether nix commands are expected to write their log in the internal-json format.
+    fn is_json(&self) -> bool {
+        !self.display && (self.quiet || self.monitor.is_some() || self.progress)
+    }
+
+    /// The arguments making a nix command write the log followed by [`Executer::execute_nix`].
+    ///
+    /// Without progress or a monitor, nix shows its log as it is & writes a copy to a file, if
+    /// it's able to. Only commands passing nix's logging flags on to nix should be given them.
+    pub fn log_args(&self, nix: &NixInfo) -> String {
+        if self.is_json() {
+            " --log-format internal-json -v".into()
+        } else if !self.display && nix.supports_json_log_path() {
+            let path = self.log_directory.join(LOG_FILE);
+            format!(" --option json-log-path {}", quote(&path.to_string_lossy()))
+        } else {
+            String::new()
+        }
+    }
+
+    /// Sets whether the errors of captured commands & the log of nix commands are only kept,
+    /// instead of being shown.
+    ///
+    /// This keeps the output of commands run at the same time from interleaving.
+    pub fn with_quiet(mut self, quiet: bool) -> Self {
+        self.quiet = quiet;
+        self
+    }
+
+    /// The messages & build output of the last nix command executed.
+    pub fn log(&self) -> &[Box<str>] {
+        &self.log
+    }
+
+    /// Executes the given nix command, keeping its log to explain failures.
+    ///
+    /// The command should be given the [`Executer::log_args`] if it accepts them. The log is
+    /// shown with nix-output-monitor if enabled & installed, as concise progress if enabled, or
+    /// as it is otherwise. With progress, the kept log is written out if the command fails. When
+    /// displaying, the command is written out as is.
+    pub fn execute_nix(&mut self, command: &str) -> Result<(), CommandError> {
+        self.log.clear();
+        if self.display {
+            return self.execute(command);
+        }
+
+        self.run_nix(command, Stdio::inherit()).map(|_| ())
+    }
+
+    /// Executes the given nix command, keeping its log like [`Executer::execute_nix`] &
+    /// returning its trimmed stdout like [`Executer::capture`].
+    pub fn capture_nix(&mut self, command: &str) -> Result<String, CommandError> {
+        self.log.clear();
+        if self.display {
+            return self.capture(command);
+        }
+
+        let output = self.run_nix(command, Stdio::piped())?;
+        Ok(String::from_utf8_lossy(&output).trim().into())
+    }
+
+    /// Runs the given nix command, keeping its log & returning its stdout if it's piped.
+    fn run_nix(&mut self, command: &str, stdout: Stdio) -> Result<Vec<u8>, CommandError> {
+        let error = |err| CommandError::ExecutionError {
+            err,
+            command: command.into(),
+        };
+        let json = self.is_json();
+        // Nix keeps its progress bar & colours when writing to the terminal itself, as long as
+        // it writes a copy of its log. Otherwise its errors are copied as they're shown.
+        let copied =
+            !json && command.contains(&*self.log_directory.join(LOG_FILE).to_string_lossy());
+        if copied {
+            std::fs::create_dir_all(&self.log_directory).map_err(error)?;
+        }
+
+        let mut monitor = match &self.monitor {
+            Some(monitor) => Some(
+                Command::new("sh")
+                    .arg("-c")
+                    .arg(&**monitor)
+                    .stdin(Stdio::piped())
+                    .spawn()
+                    .map_err(error)?,
+            ),
+            None => None,
+        };
+        let child = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .stdin(Stdio::inherit())
+            .stdout(stdout)
+            .stderr(if copied {
+                Stdio::inherit()
+            } else {
+                Stdio::piped()
+            })
+            .spawn();
+        let mut child = match child {
+            Ok(child) => child,
+            Err(err) => {
+                self.log = self.read_log_copy();
+                return Err(error(err));
+            }
+        };
+        // The output is read alongside the log, so the command never blocks on writing either.
+        let output = child.stdout.take().map(|mut stdout| {
+            std::thread::spawn(move || {
+                let mut output = Vec::new();
+                let _ = stdout.read_to_end(&mut output);
+                output
+            })
+        });
+
+        let mut state = LogState::default();
+        let mut monitor_log = monitor.as_mut().and_then(|monitor| monitor.stdin.take());
+        let mut written = Ok(());
+        if let Some(stderr) = child.stderr.take() {
+            let mut reader = BufReader::new(stderr);
+            let mut buffer = Vec::new();
+            loop {
+                buffer.clear();
+                match reader.read_until(b'\n', &mut buffer) {
+                    Ok(0) => break,
+                    Ok(_) => {}
+                    Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                    Err(_) => break,
+                }
+                let line = String::from_utf8_lossy(&buffer);
+                let line = line.trim_end_matches(['\n', '\r']);
+
+                let changed = state.handle(line);
+                if !json {
+                    let _ = std::io::stderr().write_all(&buffer);
+                } else if let Some(log) = &mut monitor_log {
+                    // The log is still kept if the monitor exits early.
+                    let _ = writeln!(log, "{line}");
+                } else if changed && written.is_ok() {
+                    written = write!(self.out, "\r\x1b[2K{}", state.summary())
+                        .and_then(|()| self.out.flush());
+                }
+            }
+        }
+        // Unlike a shell pipe to the monitor, the exit status is the command's.
+        drop(monitor_log);
+        let status = child.wait();
+        let output = output
+            .map(|output| output.join().unwrap_or_default())
+            .unwrap_or_default();
+        self.log = if copied {
+            self.read_log_copy()
+        } else {
+            state.log().to_vec()
+        };
+        let status = status.map_err(error)?;
+        written.map_err(|_| CommandError::PipeOutput)?;
+
+        let progress = match &mut monitor {
+            Some(monitor) => {
+                // The monitor exits once the command closes its log.
+                let _ = monitor.wait();
+                false
+            }
+            None => self.progress,
+        };
+        if progress {
+            writeln!(self.out).map_err(|_| CommandError::PipeOutput)?;
+        }
+
+        // If the run command failed that's an error.
+        if !status.success() {
+            if progress {
+                for line in &self.log {
+                    writeln!(self.out, "{line}").map_err(|_| CommandError::PipeOutput)?;
+                }
+            }
+            Err(CommandError::Failed {
+                command: command.into(),
+            })?;
+        }
+
+        Ok(output)
+    }
+
+    /// Reads the copy of its log the last nix command wrote, removing it.
+    fn read_log_copy(&self) -> Vec<Box<str>> {
+        let mut state = LogState::default();
+        if let Ok(log) = std::fs::read(self.log_directory.join(LOG_FILE)) {
+            for line in String::from_utf8_lossy(&log).lines() {
+                state.handle_event(line);
+            }
+        }
+        // The copy may be owned by root, but the directory it's in isn't.
+        let _ = std::fs::remove_dir_all(&self.log_directory);
+
+        state.log().to_vec()
+    }
+
+    /// Whether the commands are displayed instead of executed.
+    pub fn is_display(&self) -> bool {
+        self.display
+    }
+
+    /// Executes the given command, returning its trimmed stdout.
+    ///
+    /// When displaying, the command is written out & a shell substitution of it is returned,
+    /// so any command using the result displays as valid shell. When quiet, the command's errors
+    /// are kept in the log.
+    pub fn capture(&mut self, command: &str) -> Result<String, CommandError> {
+        if self.display {
+            writeln!(self.out, "{command}").map_err(|_| CommandError::PipeOutput)?;
+            return Ok(format!("$({command})"));
+        }
+
+        let output = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .stdin(Stdio::inherit())
+            .stderr(if self.quiet {
+                Stdio::piped()
+            } else {
+                Stdio::inherit()
+            })
+            .output()
+            .map_err(|err| CommandError::ExecutionError {
+                err,
+                command: command.into(),
+            })?;
+        if self.quiet {
+            self.log = String::from_utf8_lossy(&output.stderr)
+                .lines()
+                .map(Into::into)
+                .collect();
+        }
+
+        // If the run command failed that's an error.
+        if !output.status.success() {
+            Err(CommandError::Failed {
+                command: command.into(),
+            })?;
+        }
+
+        Ok(String::from_utf8_lossy(&output.stdout).trim().into())
+    }
+
+    /// Writes the given message to the output, for information about the commands being run.
+    pub fn report(&mut self, message: &str) -> Result<(), CommandError> {
+        writeln!(self.out, "{message}").map_err(|_| CommandError::PipeOutput)
+    }
+
+    /// Asks the user the given yes or no question, returning whether they answered yes.
+    ///
+    /// The answer is read from stdin; anything other than "y" or "yes" is a no.
+    pub fn ask(&mut self, question: &str) -> Result<bool, CommandError> {
+        write!(self.out, "{question} [y/N] ").map_err(|_| CommandError::PipeOutput)?;
+        self.out.flush().map_err(|_| CommandError::PipeOutput)?;
+
+        let mut answer = String::new();
+        std::io::stdin()
+            .read_line(&mut answer)
+            .map_err(|_| CommandError::PipeOutput)?;
+
+        Ok(is_yes(&answer))
+    }
+
+    /// Asks the user the given yes or no question, treating no answer within the timeout as a no.
+    pub fn ask_within(&mut self, question: &str, timeout: Duration) -> Result<bool, CommandError> {
+        write!(self.out, "{question} [y/N] ({}s) ", timeout.as_secs())
+            .map_err(|_| CommandError::PipeOutput)?;
+        self.out.flush().map_err(|_| CommandError::PipeOutput)?;
+
+        // Reading stdin can't time out, so it's read on another thread that may be left waiting.
+        let (sender, receiver) = mpsc::channel();
+        std::thread::spawn(move || {
+            let mut answer = String::new();
+            if std::io::stdin().read_line(&mut answer).is_ok() {
+                let _ = sender.send(answer);
+            }
+        });
+
+        match receiver.recv_timeout(timeout) {
+            Ok(answer) => Ok(is_yes(&answer)),
+            Err(_) => {
+                writeln!(self.out).map_err(|_| CommandError::PipeOutput)?;
+                Ok(false)
+            }
+        }
     }
 
     /// Returns a pre-configured command modify then execute.
@@ -52,10 +416,12 @@ pub trait Execute {
 
 impl<Out: std::io::Write> Execute for Executer<Out> {
     default fn execute(&mut self, command: &str) -> Result<(), CommandError> {
+        // Errors are still shown to the user, even though the output is written elsewhere.
         let output = self
             .generate_command()
             .arg(command)
             .stdin(Stdio::piped())
+            .stderr(Stdio::inherit())
             .output()
             .map_err(|err| CommandError::ExecutionError {
                 err,
@@ -99,3 +465,17 @@ impl Execute for Executer<Stdout> {
         Ok(())
     }
 }
+
+/// Quotes the given argument so it is passed to the shell as a single word.
+///
+/// Arguments only containing characters the shell won't interpret are returned unchanged. A
+/// "#" is only plain within a word, as it starts a comment otherwise.
+pub fn quote(arg: &str) -> Cow<'_, str> {
+    let is_plain = |char: char| char.is_ascii_alphanumeric() || "-_./=:@,+%#".contains(char);
+
+    if !arg.is_empty() && !arg.starts_with('#') && arg.chars().all(is_plain) {
+        return Cow::Borrowed(arg);
+    }
+
+    Cow::Owned(format!("'{}'", arg.replace('\'', r"'\''")))
+}
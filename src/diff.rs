@@ -0,0 +1,212 @@
+use crate::{
+    Config, Errors,
+    command_builder::{Executer, quote},
+    nix::NixInfo,
+    nix_log,
+    options::ToSwitch,
+};
+use std::fmt::Display;
+
+/// The flake attribute that builds the given target, if it is buildable without activating.
+pub fn attribute(config: &Config, target: &ToSwitch) -> Option<String> {
+    let path = &config.nix_path;
+    let identity = &config.identity;
+
+    match target {
+        ToSwitch::System { .. } => Some(format!(
+            "{path}#nixosConfigurations.{identity}.config.system.build.toplevel"
+        )),
+        ToSwitch::Home => Some(format!(
+            "{path}#homeConfigurations.{identity}.activationPackage"
+        )),
+        ToSwitch::Custom { .. } => None,
+    }
+}
+
+/// The path to the currently active generation of the given target.
+///
+/// The path may contain shell variables.
+pub fn current_generation(target: &ToSwitch) -> Option<&'static str> {
+    match target {
+        ToSwitch::System { .. } => Some("/run/current-system"),
+        ToSwitch::Home => Some("${XDG_STATE_HOME:-$HOME/.local/state}/nix/profiles/home-manager"),
+        ToSwitch::Custom { .. } => None,
+    }
+}
+
+/// Builds the given target without activating it, returning the store path of the result.
+///
+/// The extra arguments are passed to "nix build", whose failures are classified from its log.
+/// A system is built with the same generation label as when switching, so the same derivation
+/// is built. Returns [`None`] for targets that can't be built without activating them.
+pub fn build<T: std::io::Write>(
+    config: &Config,
+    target: &ToSwitch,
+    extra_args: &str,
+    nix: &NixInfo,
+    executer: &mut Executer<T>,
+) -> Result<Option<String>, Errors> {
+    let Some(attribute) = attribute(config, target) else {
+        return Ok(None);
+    };
+
+    let (label, args) = match target {
+        ToSwitch::System { offline } => {
+            let offline_arg = if *offline { " --offline" } else { "" };
+            let (label, impure) = crate::label_args(config);
+            let args = config.system.option_args();
+            (label, format!("{args}{offline_arg}{impure}"))
+        }
+        ToSwitch::Home => (String::new(), config.home.option_args()),
+        ToSwitch::Custom { .. } => (String::new(), String::new()),
+    };
+
+    let out_path = executer
+        .capture_nix(&format!(
+            "{label}nix{} build --no-link --print-out-paths {}{args}{extra_args}{}",
+            nix.nix_args(),
+            quote(&attribute),
+            executer.log_args(nix)
+        ))
+        .map_err(|err| nix_log::failure(err, executer.log(), &config.identity))?;
+
+    Ok(Some(out_path))
+}
+
+/// Builds each target & reports the changes from the currently active generation.
+pub fn diff<T: std::io::Write>(
+    config: &Config,
+    targets: &[ToSwitch],
+    nix: &NixInfo,
+    executer: &mut Executer<T>,
+) -> Result<(), Errors> {
+    for target in targets {
+        let (Some(current), Some(new)) = (
+            current_generation(target),
+            build(config, target, "", nix, executer)?,
+        ) else {
+            executer.report(&format!(
+                "Unable to show the changes for the '{}' target.",
+                target.name()
+            ))?;
+            continue;
+        };
+
+        let output = executer.capture(&format!(
+            "nix{} store diff-closures {current} {new}",
+            nix.nix_args()
+        ))?;
+
+        // There is no output to summarise when the commands are only displayed.
+        if !executer.is_display() {
+            let diff = ClosureDiff::parse(&output);
+            executer.report(&format!("Changes for '{}':\n{diff}", target.name()))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// The changes between two closures, as reported by "nix store diff-closures".
+#[derive(Debug, Default, PartialEq)]
+pub struct ClosureDiff {
+    /// Packages only in the new closure, with their versions.
+    pub added: Vec<(Box<str>, Box<str>)>,
+    /// Packages only in the old closure, with their versions.
+    pub removed: Vec<(Box<str>, Box<str>)>,
+    /// Packages whose version changed, with the old & new versions.
+    pub changed: Vec<(Box<str>, Box<str>, Box<str>)>,
+    /// The change in closure size, in KiB.
+    pub size_delta: f64,
+}
+
+impl ClosureDiff {
+    /// Parses the output of "nix store diff-closures".
+    ///
+    /// Each line is of the form "name: old → new, +1.0 KiB", where either part may be missing.
+    /// The size is coloured by nix, even when its output isn't a terminal.
+    pub fn parse(output: &str) -> Self {
+        let mut diff = Self::default();
+
+        for line in output.lines() {
+            let line = nix_log::strip_escapes(line);
+            let Some((name, rest)) = line.trim().split_once(": ") else {
+                continue;
+            };
+
+            let (versions, size) = if rest.starts_with(['+', '-']) {
+                (None, Some(rest))
+            } else {
+                match rest.rsplit_once(", ") {
+                    Some((versions, size)) if size.ends_with("KiB") => (Some(versions), Some(size)),
+                    _ => (Some(rest), None),
+                }
+            };
+
+            if let Some(size) = size {
+                diff.size_delta += size
+                    .trim_end_matches("KiB")
+                    .trim()
+                    .parse::<f64>()
+                    .unwrap_or(0.0);
+            }
+
+            let Some((old, new)) = versions.and_then(|versions| versions.split_once(" → "))
+            else {
+                continue;
+            };
+
+            match (old.trim(), new.trim()) {
+                ("∅", new) => diff.added.push((name.into(), new.into())),
+                (old, "∅") => diff.removed.push((name.into(), old.into())),
+                (old, new) => diff.changed.push((name.into(), old.into(), new.into())),
+            }
+        }
+
+        diff
+    }
+}
+
+impl Display for ClosureDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty() {
+            writeln!(f, "  No package changes.")?;
+        }
+
+        if !self.added.is_empty() {
+            writeln!(f, "  Added ({}):", self.added.len())?;
+            for (name, version) in &self.added {
+                writeln!(f, "    {name} {version}")?;
+            }
+        }
+        if !self.removed.is_empty() {
+            writeln!(f, "  Removed ({}):", self.removed.len())?;
+            for (name, version) in &self.removed {
+                writeln!(f, "    {name} {version}")?;
+            }
+        }
+        if !self.changed.is_empty() {
+            writeln!(f, "  Changed ({}):", self.changed.len())?;
+            for (name, old, new) in &self.changed {
+                writeln!(f, "    {name} {old} → {new}")?;
+            }
+        }
+
+        write!(f, "  Size: {}", format_size(self.size_delta))
+    }
+}
+
+/// Formats a signed size in KiB using the largest fitting unit.
+fn format_size(kib: f64) -> String {
+    let sign = if kib < 0.0 { "-" } else { "+" };
+    let mut size = kib.abs();
+
+    for unit in ["KiB", "MiB"] {
+        if size < 1024.0 {
+            return format!("{sign}{size:.1} {unit}");
+        }
+        size /= 1024.0;
+    }
+
+    format!("{sign}{size:.1} GiB")
+}
@@ -0,0 +1,311 @@
+use crate::{
+    Config, Errors,
+    command_builder::Executer,
+    diff,
+    history::{History, recorded_switch},
+    nix::NixInfo,
+    nix_log,
+    options::ToSwitch,
+    switch,
+};
+use std::{
+    fmt::Display,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// The options for deploying several identities in one run.
+pub struct Fleet {
+    /// The identities & groups of identities to deploy, or every configured host if empty.
+    pub members: Box<[Box<str>]>,
+    /// How many systems to build at once.
+    pub jobs: usize,
+    /// Build & activate the first member on its own, stopping if it fails.
+    pub canary: bool,
+    /// Stop activating at the first failure, instead of continuing with the other members.
+    pub fail_fast: bool,
+}
+
+/// How a stage of deploying a member ended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Stage {
+    Done,
+    /// The stage failed with the given error.
+    Failed(Box<str>),
+    /// The stage wasn't run, due to an earlier failure.
+    Skipped,
+    /// The system is built on the member's build host while activating.
+    Deferred,
+}
+
+impl Display for Stage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Stage::Done => f.pad("done"),
+            Stage::Failed(_) => f.pad("failed"),
+            Stage::Skipped => f.pad("skipped"),
+            Stage::Deferred => f.pad("deferred"),
+        }
+    }
+}
+
+/// The outcome of deploying one member of the fleet.
+#[derive(Debug, Clone)]
+pub struct Member {
+    pub identity: Box<str>,
+    /// The host the member was deployed to.
+    pub host: Box<str>,
+    pub build: Stage,
+    pub activation: Stage,
+}
+
+/// The outcome of deploying the fleet.
+pub struct Report {
+    pub members: Vec<Member>,
+}
+
+impl Report {
+    /// Converts the report into an error naming the failed members, if any.
+    pub fn into_result(self) -> Result<(), Errors> {
+        let failed: Vec<_> = self
+            .members
+            .iter()
+            .filter(|member| member.activation != Stage::Done)
+            .map(|member| &*member.identity)
+            .collect();
+
+        if failed.is_empty() {
+            return Ok(());
+        }
+        Err(Errors::FleetFailed {
+            members: failed.join(", ").into(),
+        })
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let width = |column: fn(&Member) -> &str, header: &str| {
+            self.members
+                .iter()
+                .map(|member| column(member).len())
+                .chain([header.len()])
+                .max()
+                .unwrap_or_default()
+        };
+        let identity_width = width(|member| &member.identity, "IDENTITY");
+        let host_width = width(|member| &member.host, "HOST");
+
+        writeln!(f, "Fleet summary:")?;
+        writeln!(
+            f,
+            "  {:<identity_width$}  {:<host_width$}  {:<8}  ACTIVATE",
+            "IDENTITY", "HOST", "BUILD"
+        )?;
+        for member in &self.members {
+            writeln!(
+                f,
+                "  {:<identity_width$}  {:<host_width$}  {:<8}  {}",
+                member.identity, member.host, member.build, member.activation
+            )?;
+        }
+
+        for member in &self.members {
+            for stage in [&member.build, &member.activation] {
+                if let Stage::Failed(err) = stage {
+                    writeln!(f, "{}: {err}", member.identity)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The identities to deploy, with groups expanded, in order & without duplicates.
+fn resolve(config: &Config, names: &[Box<str>]) -> Result<Vec<Box<str>>, Errors> {
+    let mut members: Vec<Box<str>> = Vec::new();
+    let names: Vec<_> = if names.is_empty() {
+        config.hosts.keys().collect()
+    } else {
+        names.iter().collect()
+    };
+
+    for name in names {
+        let identities = match config.groups.get(name) {
+            Some(group) => group.iter().collect(),
+            None => vec![name],
+        };
+        for identity in identities {
+            if !config.hosts.contains_key(identity) {
+                return Err(Errors::UnknownHost {
+                    identity: identity.clone(),
+                });
+            }
+            if !members.contains(identity) {
+                members.push(identity.clone());
+            }
+        }
+    }
+
+    Ok(members)
+}
+
+/// Builds the system of the member locally, unless it has a build host.
+///
+/// A failed build is described with the end of its log, if the executer kept it.
+fn build<T: std::io::Write>(
+    config: &Config,
+    identity: &str,
+    nix: &NixInfo,
+    executer: &mut Executer<T>,
+) -> Stage {
+    let result = config.deployment(identity).and_then(|(config, options)| {
+        if options.build_host.is_some() {
+            return Ok(Stage::Deferred);
+        }
+        diff::build(
+            &config,
+            &ToSwitch::System { offline: false },
+            "",
+            nix,
+            executer,
+        )
+        .map(|_| Stage::Done)
+    });
+
+    result.unwrap_or_else(|err| {
+        let mut text = err.to_string();
+        for line in nix_log::tail(executer.log(), config.build_log_lines) {
+            text.push('\n');
+            text.push_str(line);
+        }
+        Stage::Failed(text.into())
+    })
+}
+
+/// Builds the systems of every member, with up to `jobs` builds at once.
+///
+/// Each build keeps its own output, so builds running at once don't interleave their logs.
+/// When only displaying the commands, the builds are displayed one at a time, in order.
+fn build_all<T: std::io::Write>(
+    config: &Config,
+    members: &[Box<str>],
+    jobs: usize,
+    nix: &NixInfo,
+    executer: &mut Executer<T>,
+) -> Vec<Stage> {
+    if executer.is_display() {
+        return members
+            .iter()
+            .map(|identity| build(config, identity, nix, executer))
+            .collect();
+    }
+
+    let next = AtomicUsize::new(0);
+    let mut stages = vec![Stage::Skipped; members.len()];
+    std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..jobs.clamp(1, members.len().max(1)))
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut built = Vec::new();
+                    loop {
+                        let index = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(identity) = members.get(index) else {
+                            break built;
+                        };
+                        let mut executer = Executer::new(false, std::io::sink()).with_quiet(true);
+                        built.push((index, build(config, identity, nix, &mut executer)));
+                    }
+                })
+            })
+            .collect();
+
+        for worker in workers {
+            for (index, stage) in worker.join().expect("Build workers don't panic.") {
+                stages[index] = stage;
+            }
+        }
+    });
+
+    stages
+}
+
+/// Activates the member's system, unless it's skipped.
+fn activate<T: std::io::Write>(
+    config: &Config,
+    identity: Box<str>,
+    build: Stage,
+    skip: bool,
+    nix: &NixInfo,
+    executer: &mut Executer<T>,
+    history: Option<&History>,
+) -> Result<Member, Errors> {
+    let (member_config, mut options) = config.deployment(&identity)?;
+    options.display_command = executer.is_display();
+
+    let activation = if skip || matches!(build, Stage::Failed(_)) {
+        Stage::Skipped
+    } else {
+        let result = match history {
+            Some(history) => recorded_switch(&member_config, &options, nix, executer, history),
+            None => switch(&member_config, &options, nix, executer),
+        };
+        match result {
+            Ok(()) => Stage::Done,
+            Err(err) => Stage::Failed(err.to_string().into()),
+        }
+    };
+
+    Ok(Member {
+        host: options.target_host.unwrap_or_default(),
+        identity,
+        build,
+        activation,
+    })
+}
+
+/// Deploys the systems of the fleet members to their hosts.
+///
+/// Systems are built first, then activated one member at a time. A canary is built & activated
+/// on its own before the rest are built, which are skipped if it fails. Any switches are
+/// recorded in the history if given.
+pub fn deploy<T: std::io::Write>(
+    config: &Config,
+    fleet: &Fleet,
+    nix: &NixInfo,
+    executer: &mut Executer<T>,
+    history: Option<&History>,
+) -> Result<Report, Errors> {
+    let mut canaries = resolve(config, &fleet.members)?;
+    let rest = canaries.split_off(if fleet.canary {
+        canaries.len().min(1)
+    } else {
+        0
+    });
+
+    let mut members = Vec::new();
+    let mut stop = false;
+    // A failed canary stops the rest of the fleet.
+    let builds = build_all(config, &canaries, 1, nix, executer);
+    for (identity, build) in canaries.into_iter().zip(builds) {
+        let member = activate(config, identity, build, false, nix, executer, history)?;
+        stop |= member.activation != Stage::Done;
+        members.push(member);
+    }
+
+    let builds = if stop {
+        vec![Stage::Skipped; rest.len()]
+    } else {
+        build_all(config, &rest, fleet.jobs, nix, executer)
+    };
+    stop |= fleet.fail_fast && builds.iter().any(|build| matches!(build, Stage::Failed(_)));
+    for (identity, build) in rest.into_iter().zip(builds) {
+        let member = activate(config, identity, build, stop, nix, executer, history)?;
+        if fleet.fail_fast && member.activation != Stage::Done {
+            stop = true;
+        }
+        members.push(member);
+    }
+
+    Ok(Report { members })
+}
@@ -0,0 +1,79 @@
+use chrono::{DateTime, Local};
+use serde::Serialize;
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+/// The nix profile holding the system generations.
+pub const SYSTEM_PROFILE: &str = "/nix/var/nix/profiles/system";
+
+/// A generation of a nix profile.
+#[derive(Serialize, Debug, Clone)]
+pub struct Generation {
+    /// The number of the generation.
+    pub number: u32,
+    /// When the generation was created.
+    pub built: DateTime<Local>,
+}
+
+/// The nix profile holding the home-manager generations, if it can be located.
+pub fn home_profile() -> Option<PathBuf> {
+    let state_dir = std::env::var_os("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".local/state")))?;
+
+    let profile = state_dir.join("nix/profiles/home-manager");
+    if profile.exists() {
+        return Some(profile);
+    }
+
+    // Older home-manager versions used the per-user profile directory.
+    let user = std::env::var_os("USER")?;
+    let legacy = Path::new("/nix/var/nix/profiles/per-user")
+        .join(user)
+        .join("home-manager");
+    legacy.exists().then_some(legacy)
+}
+
+/// The currently active generation of the given profile.
+///
+/// A profile is a symlink to its current generation link, such as "system-42-link".
+pub fn current(profile: &Path) -> Option<Generation> {
+    let link = std::fs::read_link(profile).ok()?;
+    let number = parse_number(link.file_name()?.to_str()?)?;
+
+    let link = profile.parent()?.join(link);
+    let built = std::fs::symlink_metadata(link).ok()?.modified().ok()?;
+
+    Some(Generation {
+        number,
+        built: built.into(),
+    })
+}
+
+/// Parses the generation number from the name of a generation link.
+pub(crate) fn parse_number(link: &str) -> Option<u32> {
+    link.strip_suffix("-link")?.rsplit('-').next()?.parse().ok()
+}
+
+/// A generation to look up, either by number or the currently active one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GenerationQuery {
+    Current,
+    Number(u32),
+}
+
+impl FromStr for GenerationQuery {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "current" => Ok(Self::Current),
+            number => number
+                .parse()
+                .map(Self::Number)
+                .map_err(|_| format!("'{number}' is not a generation number or \"current\"")),
+        }
+    }
+}
@@ -0,0 +1,70 @@
+use std::{
+    path::Path,
+    process::Command,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// Runs git in the given repository, returning the trimmed stdout if it succeeded.
+fn run(repo: &Path, args: &[&str]) -> Option<String> {
+    output(Command::new("git"), repo, args)
+}
+
+/// Runs the git command in the given repository, returning the trimmed stdout if it succeeded.
+fn output(mut command: Command, repo: &Path, args: &[&str]) -> Option<String> {
+    let output = command.arg("-C").arg(repo).args(args).output().ok()?;
+
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8(output.stdout).ok()?.trim().into())
+}
+
+/// The branch currently checked out in the repository.
+pub fn branch(repo: &Path) -> Option<String> {
+    run(repo, &["rev-parse", "--abbrev-ref", "HEAD"])
+}
+
+/// The hash of the commit currently checked out in the repository.
+pub fn commit(repo: &Path) -> Option<String> {
+    run(repo, &["rev-parse", "HEAD"])
+}
+
+/// Whether the repository has uncommitted changes.
+pub fn is_dirty(repo: &Path) -> Option<bool> {
+    run(repo, &["status", "--porcelain"]).map(|status| !status.is_empty())
+}
+
+/// The git hash of the given file in the repository, whether or not it's committed.
+pub fn hash_file(repo: &Path, file: &str) -> Option<String> {
+    run(repo, &["hash-object", file])
+}
+
+/// The contents of the given file at the given revision of the repository.
+pub fn show_file(repo: &Path, rev: &str, file: &str) -> Option<String> {
+    run(repo, &["show", &format!("{rev}:{file}")])
+}
+
+/// The hash of the tree of tracked files in the repository, including uncommitted changes.
+///
+/// The tree is built in a temporary index, leaving the repository's own index untouched.
+pub fn worktree_hash(repo: &Path) -> Option<String> {
+    let staged = run(repo, &["write-tree"])?;
+    // Each call has its own index, as hashes may be computed concurrently.
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+    let index = std::env::temp_dir().join(format!(
+        "system-manager-index-{}-{}",
+        std::process::id(),
+        CALLS.fetch_add(1, Ordering::Relaxed)
+    ));
+    let run_in_index = |args: &[&str]| {
+        let mut command = Command::new("git");
+        command.env("GIT_INDEX_FILE", &index);
+        output(command, repo, args)
+    };
+
+    let tree = run_in_index(&["read-tree", &staged])
+        .and_then(|_| run_in_index(&["add", "--update"]))
+        .and_then(|_| run_in_index(&["write-tree"]));
+    let _ = std::fs::remove_file(&index);
+    tree
+}
@@ -0,0 +1,102 @@
+use crate::{
+    Errors,
+    command_builder::{Execute as _, Executer, quote},
+    hooks::{self, HookContext},
+    nix::NixInfo,
+};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Checks run after a system switch, rolling back to the previous generation if any fail.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct HealthChecks {
+    /// Whether to check the system after switching.
+    ///
+    /// When enabled, there must be no failed systemd units.
+    #[serde(default)]
+    pub enabled: bool,
+    /// Systemd units that must be active.
+    #[serde(default)]
+    pub required_units: Box<[Box<str>]>,
+    /// Shell commands that must succeed, with the same environment variables as hooks.
+    #[serde(default)]
+    pub commands: Box<[Box<str>]>,
+    /// How many seconds to wait for confirmation that the system works, once the checks pass.
+    ///
+    /// Without an answer in time, the system is rolled back. No confirmation is asked for
+    /// without a timeout.
+    #[serde(default)]
+    pub confirm_timeout: Option<u64>,
+}
+
+/// The shell command checking that there are no failed systemd units.
+///
+/// The command substitution is assigned first, so it fails if systemctl does.
+pub(crate) const NO_FAILED_UNITS: &str =
+    "failed=\"$(systemctl --failed --no-legend --plain)\" && test -z \"$failed\"";
+
+/// Runs the health checks, returning the first one that failed.
+pub(crate) fn check<T: std::io::Write>(
+    checks: &HealthChecks,
+    context: &HookContext,
+    executer: &mut Executer<T>,
+) -> Result<(), Box<str>> {
+    executer
+        .execute(NO_FAILED_UNITS)
+        .map_err(|_| "no failed units")?;
+
+    for unit in &checks.required_units {
+        executer
+            .execute(&format!("systemctl is-active --quiet {}", quote(unit)))
+            .map_err(|_| format!("unit '{unit}' is active"))?;
+    }
+
+    for command in &checks.commands {
+        hooks::run(std::slice::from_ref(command), context, executer)
+            .map_err(|_| command.clone())?;
+    }
+
+    if let Some(timeout) = checks.confirm_timeout
+        && !executer.is_display()
+    {
+        let confirmed = executer
+            .ask_within("Does the system still work?", Duration::from_secs(timeout))
+            .unwrap_or(false);
+        if !confirmed {
+            return Err("confirmation".into());
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks the health of a newly switched system, rolling back to the previous generation if a
+/// check fails.
+pub fn verify<T: std::io::Write>(
+    checks: &HealthChecks,
+    context: &HookContext,
+    nix: &NixInfo,
+    executer: &mut Executer<T>,
+) -> Result<(), Errors> {
+    let Err(check) = check(checks, context, executer) else {
+        return Ok(());
+    };
+    roll_back(check, nix, executer)
+}
+
+/// Rolls the system back to the previous generation, as the given health check failed.
+pub(crate) fn roll_back<T: std::io::Write>(
+    check: Box<str>,
+    nix: &NixInfo,
+    executer: &mut Executer<T>,
+) -> Result<(), Errors> {
+    executer.report(&format!(
+        "Health check failed: {check}. Rolling back to the previous generation."
+    ))?;
+    executer.execute(&format!(
+        "sudo nixos-rebuild{} switch --rollback",
+        nix.wrapper_args(true)
+    ))?;
+
+    Err(Errors::RolledBack { check })
+}
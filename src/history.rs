@@ -0,0 +1,359 @@
+use crate::{
+    Config, Errors, Progress,
+    command_builder::Executer,
+    generation::{self, SYSTEM_PROFILE},
+    git,
+    nix::NixInfo,
+    options::{Switch, ToSwitch},
+    switch_with_progress,
+    workflow::format_duration,
+};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::Display,
+    io::Write as _,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// The name of the file in the data directory holding the switch history.
+pub const HISTORY_FILE: &str = "history.jsonl";
+
+/// How a switch ended.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Success,
+    Failure,
+    Cancelled,
+    /// The switch failed its health checks & was rolled back.
+    #[serde(rename = "rolled-back")]
+    RolledBack,
+}
+
+impl Display for Outcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Outcome::Success => write!(f, "success"),
+            Outcome::Failure => write!(f, "failure"),
+            Outcome::Cancelled => write!(f, "cancelled"),
+            Outcome::RolledBack => write!(f, "rolled back"),
+        }
+    }
+}
+
+impl<T> From<&Result<T, Errors>> for Outcome {
+    fn from(value: &Result<T, Errors>) -> Self {
+        match value {
+            Ok(_) => Outcome::Success,
+            Err(Errors::Cancelled) => Outcome::Cancelled,
+            Err(Errors::RolledBack { .. }) => Outcome::RolledBack,
+            Err(_) => Outcome::Failure,
+        }
+    }
+}
+
+/// How long a stage of a switch took.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StageDuration {
+    /// The name of the stage, such as "update" or a target name.
+    pub stage: Box<str>,
+    pub seconds: f64,
+}
+
+/// A record of a switch that was run.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SwitchRecord {
+    /// When the switch started.
+    pub time: DateTime<Local>,
+    /// The names of the targets switched.
+    pub targets: Box<[Box<str>]>,
+    /// The identity that was switched.
+    pub identity: Box<str>,
+    /// The path to the nix configuration.
+    pub flake_path: Box<str>,
+    /// The git commit checked out in the nix configuration.
+    pub commit: Option<Box<str>>,
+    /// Whether the nix configuration had uncommitted changes.
+    pub dirty: Option<bool>,
+    /// The git hash of the 'flake.lock' file after any update.
+    pub lock_hash: Option<Box<str>>,
+    /// The git tree hash of the nix configuration, including uncommitted changes.
+    #[serde(default)]
+    pub tree: Option<Box<str>>,
+    /// Whether the switch included updating the 'flake.lock' file.
+    #[serde(default)]
+    pub update: bool,
+    /// Whether the system was switched without downloading any more data.
+    #[serde(default)]
+    pub offline: bool,
+    /// The active system generation after the switch.
+    pub system_generation: Option<u32>,
+    /// The active home-manager generation after the switch.
+    pub home_generation: Option<u32>,
+    /// How long each completed stage took.
+    pub durations: Box<[StageDuration]>,
+    /// How the switch ended.
+    pub outcome: Outcome,
+}
+
+impl SwitchRecord {
+    /// Creates a record of a switch from its progress & result.
+    fn new(
+        config: &Config,
+        options: &Switch,
+        time: DateTime<Local>,
+        progress: &Progress,
+        outcome: Outcome,
+    ) -> Self {
+        let flake = config.nix_path.as_std_path();
+        let switched = |name: &str| progress.targets.iter().any(|target| &**target == name);
+
+        Self {
+            time,
+            targets: progress.targets.clone().into_boxed_slice(),
+            identity: config.identity.clone(),
+            flake_path: config.nix_path.as_str().into(),
+            commit: git::commit(flake).map(Into::into),
+            dirty: git::is_dirty(flake),
+            lock_hash: git::hash_file(flake, "flake.lock").map(Into::into),
+            tree: git::worktree_hash(flake).map(Into::into),
+            update: options.update,
+            offline: options
+                .targets
+                .iter()
+                .any(|target| matches!(target, ToSwitch::System { offline: true })),
+            // The generations of other hosts can't be read locally.
+            system_generation: (switched("system") && options.target_host.is_none())
+                .then(|| generation::current(Path::new(SYSTEM_PROFILE)))
+                .flatten()
+                .map(|generation| generation.number),
+            home_generation: switched("home")
+                .then(|| {
+                    generation::home_profile().and_then(|profile| generation::current(&profile))
+                })
+                .flatten()
+                .map(|generation| generation.number),
+            durations: progress
+                .durations
+                .iter()
+                .map(|(stage, duration)| StageDuration {
+                    stage: stage.clone(),
+                    seconds: duration.as_secs_f64(),
+                })
+                .collect(),
+            outcome,
+        }
+    }
+
+    /// Whether the stage, such as "update" or a target name, completed.
+    fn completed(&self, stage: &str) -> bool {
+        self.durations
+            .iter()
+            .any(|duration| &*duration.stage == stage)
+    }
+
+    /// The total time taken by the completed stages.
+    fn total_duration(&self) -> Duration {
+        self.durations
+            .iter()
+            .map(|stage| Duration::from_secs_f64(stage.seconds))
+            .sum()
+    }
+}
+
+impl Display for SwitchRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let commit = match (&self.commit, self.dirty) {
+            (Some(commit), Some(true)) => format!("{}+dirty", short_hash(commit)),
+            (Some(commit), _) => short_hash(commit).into(),
+            (None, _) => "no commit".into(),
+        };
+        let generation = |generation: Option<u32>| {
+            generation.map_or_else(|| "-".into(), |number| number.to_string())
+        };
+
+        write!(
+            f,
+            "{}  {:<9}  {:<16}  {:<12}  {:<14}  gen {}/{}  {}",
+            self.time.format("%Y-%m-%d %H:%M"),
+            self.outcome,
+            self.identity,
+            self.targets.join(","),
+            commit,
+            generation(self.system_generation),
+            generation(self.home_generation),
+            format_duration(self.total_duration())
+        )
+    }
+}
+
+/// The abbreviated form of a git hash.
+pub fn short_hash(hash: &str) -> &str {
+    hash.get(..7).unwrap_or(hash)
+}
+
+/// Which records to include when listing the history.
+#[derive(Debug, Default)]
+pub struct Filter {
+    pub identity: Option<Box<str>>,
+    pub target: Option<Box<str>>,
+    pub outcome: Option<Outcome>,
+    /// Only include the most recent records, up to this many.
+    pub limit: Option<usize>,
+}
+
+impl Filter {
+    /// Whether the record is included by the filter.
+    fn matches(&self, record: &SwitchRecord) -> bool {
+        self.identity
+            .as_ref()
+            .is_none_or(|identity| *identity == record.identity)
+            && self
+                .target
+                .as_ref()
+                .is_none_or(|target| record.targets.contains(target))
+            && self.outcome.is_none_or(|outcome| outcome == record.outcome)
+    }
+}
+
+/// The persistent log of switches, stored as one JSON record per line.
+pub struct History {
+    path: PathBuf,
+}
+
+impl History {
+    /// Uses the history stored at the given path.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    /// Appends the record to the history.
+    pub fn append(&self, record: &SwitchRecord) -> Result<(), Errors> {
+        let error = || Errors::DataWrite {
+            path: self.path.clone().into_boxed_path(),
+        };
+
+        let mut line = serde_json::to_string(record)?;
+        line.push('\n');
+
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(|_| error())?
+            .write_all(line.as_bytes())
+            .map_err(|_| error())
+    }
+
+    /// Reads every record in the history, oldest first.
+    ///
+    /// Lines that can't be parsed are skipped.
+    pub fn records(&self) -> Vec<SwitchRecord> {
+        let Ok(text) = std::fs::read_to_string(&self.path) else {
+            return Vec::new();
+        };
+
+        text.lines()
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .collect()
+    }
+
+    /// Reads the records included by the filter, oldest first.
+    pub fn filter(&self, filter: &Filter) -> Vec<SwitchRecord> {
+        let mut records: Vec<_> = self
+            .records()
+            .into_iter()
+            .filter(|record| filter.matches(record))
+            .collect();
+
+        if let Some(limit) = filter.limit {
+            records.drain(..records.len().saturating_sub(limit));
+        }
+
+        records
+    }
+
+    /// The most recent successful switch that resulted in the given generation.
+    pub fn generation(&self, home: bool, number: u32) -> Option<SwitchRecord> {
+        self.records().into_iter().rev().find(|record| {
+            let generation = if home {
+                record.home_generation
+            } else {
+                record.system_generation
+            };
+            record.outcome == Outcome::Success && generation == Some(number)
+        })
+    }
+
+    /// The most recent record in the history.
+    pub fn last(&self) -> Option<SwitchRecord> {
+        self.records().pop()
+    }
+}
+
+/// Executes a switch, then appends a record of it to the history.
+///
+/// Nothing is recorded when the commands are only displayed.
+pub fn recorded_switch<T: std::io::Write>(
+    config: &Config,
+    options: &Switch,
+    nix: &NixInfo,
+    executer: &mut Executer<T>,
+    history: &History,
+) -> Result<(), Errors> {
+    let time = Local::now();
+    let mut progress = Progress::default();
+    let result = switch_with_progress(config, options, nix, executer, &mut progress);
+
+    if !executer.is_display() {
+        let record = SwitchRecord::new(config, options, time, &progress, (&result).into());
+        // A failed switch is more important to report than failing to record it.
+        return result.and(history.append(&record));
+    }
+
+    result
+}
+
+/// Plans a switch resuming the last switch of the identity, with only the update & targets that
+/// didn't complete.
+///
+/// The plan is refused if the nix configuration changed since, unless changes are ignored.
+pub fn resume(config: &Config, options: &Switch, history: &History) -> Result<Switch, Errors> {
+    let last = history
+        .filter(&Filter {
+            identity: Some(config.identity.clone()),
+            limit: Some(1),
+            ..Default::default()
+        })
+        .pop()
+        .filter(|record| record.outcome != Outcome::Success)
+        .ok_or_else(|| Errors::NothingToResume {
+            identity: config.identity.clone(),
+        })?;
+
+    let flake = config.nix_path.as_std_path();
+    let changed = last.tree.as_deref() != git::worktree_hash(flake).as_deref()
+        || last.lock_hash.as_deref() != git::hash_file(flake, "flake.lock").as_deref();
+    if changed && !options.ignore_changes {
+        return Err(Errors::ResumeChanged);
+    }
+
+    let mut resumed = options.clone();
+    resumed.all = false;
+    resumed.update = last.update && !last.completed("update");
+    resumed.targets = last
+        .targets
+        .iter()
+        .filter(|target| !last.completed(target))
+        .map(|target| match &**target {
+            "system" => ToSwitch::System {
+                offline: last.offline,
+            },
+            name => ToSwitch::from_name(name),
+        })
+        .collect();
+
+    Ok(resumed)
+}
@@ -0,0 +1,74 @@
+use crate::command_builder::{CommandError, Execute as _, Executer, quote};
+use camino::Utf8Path;
+use serde::{Deserialize, Serialize};
+use std::fmt::Write as _;
+
+/// Shell commands run around the stages of a switch.
+///
+/// Each hook is run in the directory of the nix configuration, & receives the context of the switch through environment variables:
+/// - `SYSTEM_MANAGER_IDENTITY`: The identity being switched.
+/// - `SYSTEM_MANAGER_PATH`: The path to the nix configuration.
+/// - `SYSTEM_MANAGER_TARGET`: The target being switched, if any.
+/// - `SYSTEM_MANAGER_OUTCOME`: "success" or "failure", once known.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Hooks {
+    /// Run before the 'flake.lock' file is updated.
+    pub before_update: Box<[Box<str>]>,
+    /// Run after the 'flake.lock' file is updated.
+    pub after_update: Box<[Box<str>]>,
+    /// Run before each target is switched.
+    pub before_target: Box<[Box<str>]>,
+    /// Run after each target is switched successfully.
+    pub after_target: Box<[Box<str>]>,
+    /// Run when any stage of the switch fails.
+    pub on_failure: Box<[Box<str>]>,
+}
+
+/// The context passed to hooks through environment variables.
+pub struct HookContext<'a> {
+    pub identity: &'a str,
+    pub path: &'a Utf8Path,
+    pub target: Option<&'a str>,
+    pub outcome: Option<&'a str>,
+}
+
+impl HookContext<'_> {
+    /// Formats the context as environment variable assignments for a shell command.
+    fn env(&self) -> String {
+        let mut env = format!(
+            "SYSTEM_MANAGER_IDENTITY={} SYSTEM_MANAGER_PATH={}",
+            quote(self.identity),
+            quote(self.path.as_str())
+        );
+
+        if let Some(target) = self.target {
+            let _ = write!(env, " SYSTEM_MANAGER_TARGET={}", quote(target));
+        }
+        if let Some(outcome) = self.outcome {
+            let _ = write!(env, " SYSTEM_MANAGER_OUTCOME={}", quote(outcome));
+        }
+
+        env
+    }
+}
+
+/// Runs each of the given hooks in order with the given context, in the directory of the nix
+/// configuration.
+pub fn run<T: std::io::Write>(
+    hooks: &[Box<str>],
+    context: &HookContext,
+    executer: &mut Executer<T>,
+) -> Result<(), CommandError> {
+    if hooks.is_empty() {
+        return Ok(());
+    }
+
+    let directory = quote(context.path.as_str());
+    let env = context.env();
+    for hook in hooks {
+        executer.execute(&format!("cd {directory} && {env} sh -c {}", quote(hook)))?;
+    }
+
+    Ok(())
+}
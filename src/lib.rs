@@ -1,16 +1,44 @@
 #![feature(min_specialization)]
 
+pub mod bisect;
+pub mod cache;
+pub mod check;
 pub mod command_builder;
+pub mod diff;
+pub mod fleet;
+pub mod generation;
+pub mod git;
+pub mod health;
+pub mod history;
+pub mod hooks;
+pub mod lock;
+pub mod nix;
+pub mod nix_log;
 pub mod options;
+pub mod outdated;
+pub mod status;
 #[cfg(test)]
 mod test;
+pub mod workflow;
 
-use crate::options::ToSwitch;
+use crate::options::{Switch, ToSwitch};
 use app_dirs2::AppInfo;
+use cache::{Fingerprint, SwitchCache};
 use camino::{Utf8Path, Utf8PathBuf};
-use command_builder::{CommandError, Execute as _, Executer};
+use chrono::Local;
+use command_builder::{CommandError, Execute as _, Executer, quote};
+use health::HealthChecks;
+use hooks::{HookContext, Hooks};
+use lock::{FlakeLock, Staleness};
+use nix::NixInfo;
 use serde::{Deserialize, Serialize};
-use std::path::Path;
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    path::Path,
+    time::{Duration, Instant},
+};
+use workflow::Step;
 
 /// Holds data for [app_dirs2].
 pub const APP_INFO: AppInfo = AppInfo {
@@ -41,6 +69,8 @@ pub enum Errors {
     ConfigParse(#[from] serde_json::Error),
     #[error("Unable to write config to path: {path}")]
     ConfigWrite { path: Box<Path> },
+    #[error("Unable to write data to path: {path}")]
+    DataWrite { path: Box<Path> },
 
     #[error("{error}")]
     InvalidPath { error: std::io::Error },
@@ -49,6 +79,89 @@ pub enum Errors {
     )]
     NotUTFPath,
 
+    #[error(
+        "Unknown switch target: '{name}'. Custom targets need to be defined in the config before use."
+    )]
+    UnknownTarget { name: Box<str> },
+    #[error(
+        "The custom target '{name}' has the name of a built-in target. Rename it in the config."
+    )]
+    ReservedTarget { name: Box<str> },
+    #[error("The 'all' target already switches every target, so it can't be given with others.")]
+    MixedAllTarget,
+    #[error("Unknown workflow: '{name}'. Workflows need to be defined in the config before use.")]
+    UnknownWorkflow { name: Box<str> },
+    #[error("Switch cancelled.")]
+    Cancelled,
+    #[error("Unable to determine the active {kind} generation.")]
+    NoActiveGeneration { kind: &'static str },
+    #[error("No successful switch to {kind} generation {number} has been recorded.")]
+    UnrecordedGeneration { kind: &'static str, number: u32 },
+    #[error("Unable to read the lock file: '{reference}'.")]
+    LockRead { reference: Box<str> },
+    #[error("The lock files pin the same inputs, there is nothing to bisect.")]
+    NoLockChanges,
+    #[error("The '{name}' target can't be built on its own.")]
+    UnbuildableTarget { name: Box<str> },
+    #[error("Inputs in 'flake.lock' are stale: {inputs}. Update them before switching.")]
+    StaleInputs { inputs: Box<str> },
+    #[error(
+        "The health check '{check}' failed after switching, so the system was rolled back to the previous generation."
+    )]
+    RolledBack { check: Box<str> },
+    #[error("Every target was built, but activating failed for: {targets}.")]
+    ActivationFailed { targets: Box<str> },
+    #[error(
+        "No host is configured for the identity '{identity}'. Hosts need to be defined in the config before deploying."
+    )]
+    UnknownHost { identity: Box<str> },
+    #[error("Unable to list the configurations in the flake.")]
+    UnlistedConfigurations,
+    #[error("Checks failed for: {checks}.")]
+    ChecksFailed { checks: Box<str> },
+    #[error("Deploying failed for: {members}.")]
+    FleetFailed { members: Box<str> },
+    #[error("Only system switches can be deployed to another host.")]
+    RemoteTarget,
+    #[error("The last switch of '{identity}' didn't fail, so there is nothing to resume.")]
+    NothingToResume { identity: Box<str> },
+    #[error(
+        "The nix configuration changed since the failed switch. Use '--ignore-changes' to resume it anyway."
+    )]
+    ResumeChanged,
+    #[error(
+        "Evaluating the configuration failed at {location}: {message}\nHint: fix the nix code at that location, then switch again."
+    )]
+    EvaluationFailed {
+        location: Box<str>,
+        message: Box<str>,
+    },
+    #[error("Building '{derivation}' failed.\nHint: {}", build_log_hint(derivation, .log))]
+    BuildFailed {
+        derivation: Box<str>,
+        /// Where the full build log is saved, if it is.
+        log: Option<Box<Path>>,
+    },
+    #[error(
+        "Downloading failed: {detail}\nHint: check the network connection & the configured substituters, then switch again."
+    )]
+    DownloadFailed { detail: Box<str> },
+    #[error(
+        "Home-manager would overwrite the existing file '{path}'.\nHint: move or delete the file, or pass '-b backup' to home-manager to back it up."
+    )]
+    ClobberedFile { path: Box<str> },
+    #[error(
+        "The flake doesn't provide '{attribute}'.\nHint: check that the identity '{identity}' names a configuration in the flake, or change it with 'system-manager identity set'."
+    )]
+    MissingAttribute {
+        attribute: Box<str>,
+        identity: Box<str>,
+    },
+    #[error(
+        "{detail}\nHint: flakes only see files tracked by git, so 'git add' new files & commit or stash other changes."
+    )]
+    GitTreeNotClean { detail: Box<str> },
+
     #[error(transparent)]
     CommandError(#[from] CommandError),
 }
@@ -60,6 +173,153 @@ pub struct Config {
     pub identity: Box<str>,
     /// The path to the nix configuration.
     pub nix_path: Box<Utf8Path>,
+    /// Additional arguments for system switches.
+    #[serde(default)]
+    pub system: TargetConfig,
+    /// Additional arguments for home-manager switches.
+    #[serde(default)]
+    pub home: TargetConfig,
+    /// Shell commands run around the stages of a switch.
+    #[serde(default)]
+    pub hooks: Hooks,
+    /// User-defined switch targets, by name.
+    #[serde(default)]
+    pub targets: BTreeMap<Box<str>, CustomTarget>,
+    /// The targets switched by "switch all", in order.
+    #[serde(default = "default_target_order")]
+    pub target_order: Box<[Box<str>]>,
+    /// Whether to label system generations with the git commit of the nix configuration.
+    ///
+    /// This requires evaluating the configuration with "--impure".
+    #[serde(default)]
+    pub label_generations: bool,
+    /// Whether to ask for confirmation before activating a system switch.
+    #[serde(default)]
+    pub confirm_system_switch: bool,
+    /// Named sequences of steps, run with the "run" sub command.
+    #[serde(default)]
+    pub workflows: BTreeMap<Box<str>, Box<[Step]>>,
+    /// When inputs pinned in 'flake.lock' are warned about for being old.
+    #[serde(default)]
+    pub stale_lock: Staleness,
+    /// Whether to build every target before activating any of them.
+    #[serde(default)]
+    pub build_first: bool,
+    /// Whether to render concise progress of nix builds, instead of their full output.
+    #[serde(default)]
+    pub progress: bool,
+    /// Whether to show nix builds with nix-output-monitor, when it's installed.
+    #[serde(default)]
+    pub nom: bool,
+    /// How many lines of the build log of a failed derivation to print.
+    #[serde(default = "default_build_log_lines")]
+    pub build_log_lines: usize,
+    /// Whether to check & evaluate the flake before switching, even without "--preflight".
+    #[serde(default)]
+    pub preflight: bool,
+    /// Checks run after switching the system, rolling it back if any fail.
+    #[serde(default)]
+    pub health_checks: HealthChecks,
+    /// The machines other identities are deployed to, by identity.
+    #[serde(default)]
+    pub hosts: BTreeMap<Box<str>, Host>,
+    /// Named groups of identities with hosts, deployed together with the "fleet" sub command.
+    #[serde(default)]
+    pub groups: BTreeMap<Box<str>, Box<[Box<str>]>>,
+}
+
+/// The names of the targets the switch sub command provides itself.
+const BUILT_IN_TARGETS: [&str; 4] = ["system", "home", "both", "all"];
+
+/// The targets switched by "switch all" if none are configured.
+fn default_target_order() -> Box<[Box<str>]> {
+    Box::new(["system".into(), "home".into()])
+}
+
+/// Points to the saved build log of a failed derivation, or how to see it otherwise.
+fn build_log_hint(derivation: &str, log: &Option<Box<Path>>) -> String {
+    match log {
+        Some(path) => format!("the full build log is saved to '{}'.", path.display()),
+        None => format!("run 'nix log {derivation}' to see why."),
+    }
+}
+
+/// How many lines of failed build logs are printed if not configured.
+fn default_build_log_lines() -> usize {
+    25
+}
+
+/// A user-defined switch target.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CustomTarget {
+    /// The shell command that performs the switch.
+    ///
+    /// "{path}" & "{identity}" are replaced with the configured values.
+    pub command: Box<str>,
+    /// Whether the command needs to be run with sudo.
+    #[serde(default)]
+    pub privileged: bool,
+}
+
+/// A machine an identity is deployed to over SSH.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Host {
+    /// The SSH address the system is activated on, such as "root@example.com".
+    pub target_host: Box<str>,
+    /// The SSH address the system is built on, instead of locally.
+    #[serde(default)]
+    pub build_host: Option<Box<str>>,
+    /// Whether to activate with sudo on the target host, for users other than root.
+    #[serde(default)]
+    pub use_remote_sudo: bool,
+}
+
+impl CustomTarget {
+    /// Formats the command with the placeholders replaced.
+    fn command(&self, config: &Config) -> String {
+        let command = self
+            .command
+            .replace("{path}", &quote(config.nix_path.as_str()))
+            .replace("{identity}", &quote(&config.identity));
+
+        if self.privileged {
+            format!("sudo {command}")
+        } else {
+            command
+        }
+    }
+}
+
+/// Additional arguments for a switch target.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct TargetConfig {
+    /// Arguments appended to the switch command, such as "--show-trace".
+    pub extra_args: Box<[Box<str>]>,
+    /// Nix options passed to the switch command with "--option <name> <value>".
+    pub nix_options: BTreeMap<Box<str>, Box<str>>,
+}
+
+impl TargetConfig {
+    /// Formats the configured arguments, followed by the given passthrough arguments.
+    fn args(&self, passthrough: &[Box<str>]) -> String {
+        let mut args = self.option_args();
+
+        for arg in self.extra_args.iter().chain(passthrough) {
+            let _ = write!(args, " {}", quote(arg));
+        }
+
+        args
+    }
+
+    /// Formats only the configured nix options, for commands that invoke nix directly.
+    fn option_args(&self) -> String {
+        let mut args = String::new();
+        for (name, value) in &self.nix_options {
+            let _ = write!(args, " --option {} {}", quote(name), quote(value));
+        }
+        args
+    }
 }
 
 impl Default for Config {
@@ -71,19 +331,93 @@ impl Default for Config {
                 .and_then(|var| Utf8PathBuf::from_path_buf(var).ok())
                 .map(|var| var.into_boxed_path())
                 .unwrap_or_else(|| Utf8Path::new("").into()),
+            system: TargetConfig::default(),
+            home: TargetConfig::default(),
+            hooks: Hooks::default(),
+            targets: BTreeMap::new(),
+            target_order: default_target_order(),
+            label_generations: false,
+            confirm_system_switch: false,
+            workflows: BTreeMap::new(),
+            stale_lock: Staleness::default(),
+            build_first: false,
+            progress: false,
+            nom: false,
+            build_log_lines: default_build_log_lines(),
+            preflight: false,
+            health_checks: HealthChecks::default(),
+            hosts: BTreeMap::new(),
+            groups: BTreeMap::new(),
         }
     }
 }
 
 impl Config {
     /// Parses the [`Config`] from the given filepath.
+    ///
+    /// Custom targets can't use the names of the built-in targets, which would shadow them.
     pub fn parse(filepath: &Path) -> Result<Self, Errors> {
         use std::fs::read_to_string;
-        Ok(serde_json::from_str(&read_to_string(filepath).map_err(
-            |_| Errors::ConfigFileRead {
+        let config: Self = serde_json::from_str(&read_to_string(filepath).map_err(|_| {
+            Errors::ConfigFileRead {
                 path: filepath.into(),
-            },
-        )?)?)
+            }
+        })?)?;
+
+        if let Some(name) = config
+            .targets
+            .keys()
+            .find(|name| BUILT_IN_TARGETS.contains(&&***name))
+        {
+            return Err(Errors::ReservedTarget { name: name.clone() });
+        }
+        Ok(config)
+    }
+
+    /// Resolves the targets to switch, checking that any custom targets exist.
+    fn resolve_targets(&self, options: &Switch) -> Result<Vec<ToSwitch>, Errors> {
+        let targets: Vec<ToSwitch> = if options.all {
+            self.target_order
+                .iter()
+                .map(|name| ToSwitch::from_name(name))
+                .collect()
+        } else {
+            options.targets.to_vec()
+        };
+
+        for target in &targets {
+            if let ToSwitch::Custom { name } = target
+                && !self.targets.contains_key(name)
+            {
+                return Err(Errors::UnknownTarget { name: name.clone() });
+            }
+        }
+
+        Ok(targets)
+    }
+
+    /// The config & switch options deploying the system of the identity to its configured host.
+    pub fn deployment(&self, identity: &str) -> Result<(Self, Switch), Errors> {
+        let host = self
+            .hosts
+            .get(identity)
+            .ok_or_else(|| Errors::UnknownHost {
+                identity: identity.into(),
+            })?;
+
+        let config = Self {
+            identity: identity.into(),
+            ..self.clone()
+        };
+        let options = Switch {
+            targets: Box::new([ToSwitch::System { offline: false }]),
+            target_host: Some(host.target_host.clone()),
+            build_host: host.build_host.clone(),
+            use_remote_sudo: host.use_remote_sudo,
+            ..Default::default()
+        };
+
+        Ok((config, options))
     }
 
     /// Writes the given config to the given file.
@@ -96,43 +430,511 @@ impl Config {
     }
 }
 
+/// The progress of a switch, for reporting failures & recording history.
+#[derive(Debug, Default)]
+pub struct Progress {
+    /// The names of the targets being switched, once resolved.
+    pub targets: Vec<Box<str>>,
+    /// The target currently being switched.
+    pub stage: Option<Box<str>>,
+    /// The stages that completed, with how long they took.
+    pub durations: Vec<(Box<str>, Duration)>,
+}
+
 /// Executes shell commands to perform a nix switch.
+///
+/// If any stage fails, the failure hooks are run before the error is returned.
 pub fn switch<T: std::io::Write>(
+    config: &Config,
+    options: &Switch,
+    nix: &NixInfo,
+    executer: &mut Executer<T>,
+) -> Result<(), Errors> {
+    switch_with_progress(config, options, nix, executer, &mut Progress::default())
+}
+
+/// Executes shell commands to perform a nix switch, recording its progress.
+///
+/// If any stage fails, the failure hooks are run before the error is returned.
+pub fn switch_with_progress<T: std::io::Write>(
+    config: &Config,
+    options: &Switch,
+    nix: &NixInfo,
+    executer: &mut Executer<T>,
+    progress: &mut Progress,
+) -> Result<(), Errors> {
+    let err = match switch_stages(config, options, nix, executer, progress) {
+        Ok(()) => return Ok(()),
+        // Declining the switch isn't a failure.
+        Err(Errors::Cancelled) => return Err(Errors::Cancelled),
+        Err(err) => err,
+    };
+
+    let context = HookContext {
+        identity: &config.identity,
+        path: &config.nix_path,
+        target: progress.stage.as_deref(),
+        outcome: Some("failure"),
+    };
+    // The original error is more useful than any error from the failure hooks.
+    let _ = hooks::run(&config.hooks.on_failure, &context, executer);
+
+    Err(err)
+}
+
+/// Updates the 'flake.lock' file, running the update hooks around it.
+pub fn update<T: std::io::Write>(
+    config: &Config,
+    nix: &NixInfo,
+    executer: &mut Executer<T>,
+) -> Result<(), Errors> {
+    let context = HookContext {
+        identity: &config.identity,
+        path: &config.nix_path,
+        target: None,
+        outcome: None,
+    };
+
+    hooks::run(&config.hooks.before_update, &context, executer)?;
+    executer
+        .execute_nix(&format!(
+            "nix{} flake update --flake {}{}",
+            nix.nix_args(),
+            config.nix_path,
+            executer.log_args(nix)
+        ))
+        .map_err(|err| nix_log::failure(err, executer.log(), &config.identity))?;
+    hooks::run(&config.hooks.after_update, &context, executer)?;
+
+    Ok(())
+}
+
+/// Checks the flake without building anything, then evaluates the derivation of each target.
+fn preflight<T: std::io::Write>(
     config: &Config,
     targets: &[ToSwitch],
-    update: bool,
-    mut executer: Executer<T>,
+    nix: &NixInfo,
+    executer: &mut Executer<T>,
 ) -> Result<(), Errors> {
-    let path = config.nix_path.clone();
+    executer
+        .execute_nix(&format!(
+            "nix{} flake check --no-build {}{}",
+            nix.nix_args(),
+            quote(config.nix_path.as_str()),
+            executer.log_args(nix)
+        ))
+        .map_err(|err| nix_log::failure(err, executer.log(), &config.identity))?;
 
+    for target in targets {
+        let Some(attribute) = diff::attribute(config, target) else {
+            continue;
+        };
+        let args = match target {
+            ToSwitch::Home => config.home.option_args(),
+            _ => config.system.option_args(),
+        };
+        executer
+            .capture_nix(&format!(
+                "nix{} eval --raw {}{args}{}",
+                nix.nix_args(),
+                quote(&format!("{attribute}.drvPath")),
+                executer.log_args(nix)
+            ))
+            .map_err(|err| nix_log::failure(err, executer.log(), &config.identity))?;
+    }
+
+    Ok(())
+}
+
+/// Warns about inputs in 'flake.lock' older than the configured threshold.
+///
+/// Stale inputs are an error instead if the config says so.
+fn check_staleness<T: std::io::Write>(
+    config: &Config,
+    executer: &mut Executer<T>,
+) -> Result<(), Errors> {
+    let Some(lock) = FlakeLock::read(config.nix_path.as_std_path()) else {
+        return Ok(());
+    };
+    let inputs = lock.inputs();
+    let stale = config.stale_lock.stale(&inputs, Local::now());
+    if stale.is_empty() {
+        return Ok(());
+    }
+
+    let stale = status::describe_stale(&stale);
+    if config.stale_lock.error {
+        return Err(Errors::StaleInputs {
+            inputs: stale.into(),
+        });
+    }
+    executer.report(&format!(
+        "Warning: Inputs in 'flake.lock' are stale: {stale}."
+    ))?;
+    Ok(())
+}
+
+/// A generation label identifying the checked out commit of the nix configuration.
+///
+/// Labels may only contain letters, numbers & ":_.-".
+fn generation_label(config: &Config) -> Option<String> {
+    let flake = config.nix_path.as_std_path();
+    let commit = git::commit(flake)?;
+    let dirty = if git::is_dirty(flake)? { "-dirty" } else { "" };
+    Some(format!("git-{}{dirty}", history::short_hash(&commit)))
+}
+
+/// The environment & argument labelling a new system generation, if enabled in the config.
+///
+/// The label is read from the environment while evaluating, so evaluation has to be impure.
+fn label_args(config: &Config) -> (String, &'static str) {
+    config
+        .label_generations
+        .then(|| generation_label(config))
+        .flatten()
+        .map(|label| (format!("env NIXOS_LABEL_VERSION={label} "), " --impure"))
+        .unwrap_or_default()
+}
+
+/// Shows the switch plan & asks the user whether to continue with activation.
+///
+/// The user isn't asked when displaying commands, as nothing is activated.
+fn confirm<T: std::io::Write>(
+    config: &Config,
+    options: &Switch,
+    targets: &[ToSwitch],
+    executer: &mut Executer<T>,
+) -> Result<(), Errors> {
+    let targets = targets
+        .iter()
+        .map(ToSwitch::name)
+        .collect::<Vec<_>>()
+        .join(", ");
+    let update = if options.update { "yes" } else { "no" };
+
+    let host = options
+        .target_host
+        .as_ref()
+        .map(|host| format!("\n  Host: {host}"))
+        .unwrap_or_default();
+
+    executer.report(&format!(
+        "Switch plan:\n  Identity: {}{host}\n  Flake: {}\n  Update inputs: {update}\n  Targets: {targets}",
+        config.identity, config.nix_path
+    ))?;
+
+    if executer.is_display() || executer.ask("Proceed with the switch?")? {
+        Ok(())
+    } else {
+        Err(Errors::Cancelled)
+    }
+}
+
+/// Executes each stage of the switch, recording the progress made.
+fn switch_stages<T: std::io::Write>(
+    config: &Config,
+    options: &Switch,
+    nix: &NixInfo,
+    executer: &mut Executer<T>,
+    progress: &mut Progress,
+) -> Result<(), Errors> {
+    let mut targets = config.resolve_targets(options)?;
+    progress.targets = targets.iter().map(|target| target.name().into()).collect();
+
+    if options.target_host.is_some()
+        && targets
+            .iter()
+            .any(|target| !matches!(target, ToSwitch::System { .. }))
+    {
+        return Err(Errors::RemoteTarget);
+    }
+
+    // Evaluation errors are shown before the sudo prompt, rather than after it.
+    if options.preflight || config.preflight {
+        let start = Instant::now();
+        preflight(config, &targets, nix, executer)?;
+        progress
+            .durations
+            .push(("preflight".into(), start.elapsed()));
+    }
+
+    if options.diff && !options.update {
+        let start = Instant::now();
+        diff::diff(config, &targets, nix, executer)?;
+        progress.durations.push(("diff".into(), start.elapsed()));
+    }
+
+    // The plan is confirmed before anything is changed, including 'flake.lock'.
     let switches_system = targets
         .iter()
         .any(|target| matches!(target, ToSwitch::System { .. }));
+    if !options.yes && (options.confirm || (config.confirm_system_switch && switches_system)) {
+        confirm(config, options, &targets, executer)?;
+    }
+
+    // The cache compares with the active generations of the local machine.
+    let mut cache = options
+        .cache
+        .as_deref()
+        .filter(|_| !executer.is_display() && options.target_host.is_none())
+        .map(SwitchCache::open);
+
+    // Without an update, the targets that are up to date are known before sudo is requested.
+    if let Some(cache) = &cache
+        && !options.update
+    {
+        skip_up_to_date(
+            config,
+            options,
+            &mut targets,
+            cache,
+            nix,
+            executer,
+            progress,
+        )?;
+    }
+
+    let requires_sudo = targets.iter().any(|target| match target {
+        // Remote systems are activated with the privileges of the SSH user.
+        ToSwitch::System { .. } => options.target_host.is_none(),
+        ToSwitch::Home => false,
+        ToSwitch::Custom { name } => config.targets[name].privileged,
+    });
 
-    if switches_system {
+    if requires_sudo {
         executer.execute("echo 'Sudo perms required for system rebuild.'")?;
         executer.execute("sudo echo 'Sudo perms given for system rebuild.'")?;
     }
 
-    if update {
-        executer.execute(&format!("nix flake update --flake {path}"))?;
+    if options.update {
+        let start = Instant::now();
+        update(config, nix, executer)?;
+        progress.durations.push(("update".into(), start.elapsed()));
     }
 
-    for target in targets {
-        executer.execute(&
-        match target {
-            ToSwitch::Home => {
-                format!("home-manager switch --flake {path}#{}", config.identity)
-            }
-            ToSwitch::System {offline} => {
-                let offline_arg = if *offline {" --offline"} else {""};
-                format!(
-                    "sudo nixos-rebuild --option experimental-features 'nix-command flakes pipe-operators' switch --flake {path}#{}{offline_arg}", config.identity
+    if let Some(cache) = &cache
+        && options.update
+    {
+        skip_up_to_date(
+            config,
+            options,
+            &mut targets,
+            cache,
+            nix,
+            executer,
+            progress,
+        )?;
+    }
+
+    check_staleness(config, executer)?;
+
+    // The changes of updated inputs can only be shown once they're updated.
+    if options.diff && options.update {
+        let start = Instant::now();
+        diff::diff(config, &targets, nix, executer)?;
+        progress.durations.push(("diff".into(), start.elapsed()));
+    }
+
+    let build_first = options.build_first || config.build_first;
+    if build_first {
+        let start = Instant::now();
+        for target in &targets {
+            progress.stage = Some(target.name().into());
+            diff::build(config, target, "", nix, executer).map_err(|err| {
+                nix_log::show_build_log(
+                    err,
+                    config.build_log_lines,
+                    options.build_logs.as_deref(),
+                    nix,
+                    executer,
                 )
+            })?;
+        }
+        progress.stage = None;
+        progress.durations.push(("build".into(), start.elapsed()));
+    }
+
+    if !build_first {
+        for target in &targets {
+            let start = Instant::now();
+            progress.stage = Some(target.name().into());
+            activate(config, options, target, cache.as_mut(), nix, executer)?;
+            progress
+                .durations
+                .push((target.name().into(), start.elapsed()));
+        }
+        progress.stage = None;
+        return Ok(());
+    }
+
+    // Every target is built, so a failure to activate one doesn't stop the others.
+    let mut failed = Vec::new();
+    for target in &targets {
+        let start = Instant::now();
+        progress.stage = Some(target.name().into());
+        match activate(config, options, target, cache.as_mut(), nix, executer) {
+            Ok(()) => progress
+                .durations
+                .push((target.name().into(), start.elapsed())),
+            Err(err) => {
+                executer.report(&format!("{}: activation failed: {err}", target.name()))?;
+                failed.push(target.name());
             }
-        },
-        )?;
+        }
+    }
+
+    if failed.is_empty() {
+        progress.stage = None;
+        return Ok(());
     }
+    let failed: Box<str> = failed.join(", ").into();
+    progress.stage = Some(failed.clone());
+    Err(Errors::ActivationFailed { targets: failed })
+}
+
+/// Removes the targets that are up to date in the cache, unless the switch is forced.
+///
+/// Skipped targets are reported, & recorded as completed.
+fn skip_up_to_date<T: std::io::Write>(
+    config: &Config,
+    options: &Switch,
+    targets: &mut Vec<ToSwitch>,
+    cache: &SwitchCache,
+    nix: &NixInfo,
+    executer: &mut Executer<T>,
+    progress: &mut Progress,
+) -> Result<(), Errors> {
+    if options.force {
+        return Ok(());
+    }
+
+    let mut remaining = Vec::new();
+    for target in targets.drain(..) {
+        let up_to_date = !matches!(target, ToSwitch::Custom { .. })
+            && Fingerprint::new(config, &switch_command(config, options, &target, nix))
+                .is_some_and(|fingerprint| cache.is_up_to_date(config, &target, &fingerprint));
+
+        if up_to_date {
+            executer.report(&format!("{}: up to date", target.name()))?;
+            progress
+                .durations
+                .push((target.name().into(), Duration::ZERO));
+        } else {
+            remaining.push(target);
+        }
+    }
+    *targets = remaining;
+
+    Ok(())
+}
+
+/// The command that switches the target.
+fn switch_command(config: &Config, options: &Switch, target: &ToSwitch, nix: &NixInfo) -> String {
+    let path = &config.nix_path;
+    match target {
+        ToSwitch::Home => {
+            let features = nix.wrapper_args(false);
+            let args = config.home.args(&options.extra_args);
+            format!(
+                "home-manager{features} switch --flake {path}#{}{args}",
+                config.identity
+            )
+        }
+        ToSwitch::System { offline } => {
+            let offline_arg = if *offline { " --offline" } else { "" };
+            let local = options.target_host.is_none();
+            let sudo = if local { "sudo " } else { "" };
+            let features = nix.wrapper_args(local);
+            let args = config.system.args(&options.extra_args);
+            let (label, impure) = label_args(config);
+            format!(
+                "{sudo}{label}nixos-rebuild{features} switch --flake {path}#{}{offline_arg}{impure}{}{args}",
+                config.identity,
+                remote_args(options)
+            )
+        }
+        ToSwitch::Custom { name } => {
+            let args = TargetConfig::default().args(&options.extra_args);
+            format!("{}{args}", config.targets[name].command(config))
+        }
+    }
+}
+
+/// Formats the arguments deploying a system switch to other hosts.
+fn remote_args(options: &Switch) -> String {
+    let mut args = String::new();
+    if let Some(host) = &options.target_host {
+        let _ = write!(args, " --target-host {}", quote(host));
+    }
+    if let Some(host) = &options.build_host {
+        let _ = write!(args, " --build-host {}", quote(host));
+    }
+    if options.use_remote_sudo {
+        args.push_str(" --use-remote-sudo");
+    }
+    args
+}
+
+/// Switches the target, running its hooks & health checks.
+///
+/// The target is recorded in the cache once switched, if there is one.
+fn activate<T: std::io::Write>(
+    config: &Config,
+    options: &Switch,
+    target: &ToSwitch,
+    mut cache: Option<&mut SwitchCache>,
+    nix: &NixInfo,
+    executer: &mut Executer<T>,
+) -> Result<(), Errors> {
+    let mut context = HookContext {
+        identity: &config.identity,
+        path: &config.nix_path,
+        target: Some(target.name()),
+        outcome: None,
+    };
+    let command = switch_command(config, options, target, nix);
+
+    let fingerprint = cache
+        .as_ref()
+        .filter(|_| !matches!(target, ToSwitch::Custom { .. }))
+        .and_then(|_| Fingerprint::new(config, &command));
+
+    hooks::run(&config.hooks.before_target, &context, executer)?;
+    match target {
+        ToSwitch::System { .. } | ToSwitch::Home => {
+            // Home-manager doesn't pass nix's logging flags on to nix.
+            let log_args = match target {
+                ToSwitch::System { .. } => executer.log_args(nix),
+                _ => String::new(),
+            };
+            if let Err(err) = executer.execute_nix(&format!("{command}{log_args}")) {
+                let err = nix_log::failure(err, executer.log(), &config.identity);
+                return Err(nix_log::show_build_log(
+                    err,
+                    config.build_log_lines,
+                    options.build_logs.as_deref(),
+                    nix,
+                    executer,
+                ));
+            }
+        }
+        ToSwitch::Custom { .. } => executer.execute(&command)?,
+    }
+    // The health checks inspect the local machine.
+    if matches!(target, ToSwitch::System { .. })
+        && config.health_checks.enabled
+        && options.target_host.is_none()
+    {
+        health::verify(&config.health_checks, &context, nix, executer)?;
+    }
+
+    if let (Some(cache), Some(fingerprint)) = (&mut cache, fingerprint) {
+        // The cache only saves time, so failing to write it doesn't fail the switch.
+        let _ = cache.record(config, target, fingerprint);
+    }
+
+    context.outcome = Some("success");
+    hooks::run(&config.hooks.after_target, &context, executer)?;
 
     Ok(())
 }
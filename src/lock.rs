@@ -0,0 +1,236 @@
+use chrono::{DateTime, Local, TimeDelta};
+use serde::{Deserialize, Serialize, de::IgnoredAny};
+use serde_json::Value;
+use std::{collections::BTreeMap, path::Path};
+
+/// The parsed contents of a 'flake.lock' file.
+#[derive(Deserialize, Debug)]
+pub struct FlakeLock {
+    nodes: BTreeMap<Box<str>, Node>,
+    root: Box<str>,
+}
+
+/// A node in the lock graph.
+#[derive(Deserialize, Debug)]
+struct Node {
+    #[serde(default)]
+    inputs: BTreeMap<Box<str>, InputRef>,
+    locked: Option<Locked>,
+}
+
+/// A reference to another node, either directly or by following a path of inputs.
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum InputRef {
+    Node(Box<str>),
+    Follows(IgnoredAny),
+}
+
+/// The locked revision of an input.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Locked {
+    last_modified: Option<i64>,
+    rev: Option<Box<str>>,
+}
+
+/// A direct input of the flake, as pinned in the lock file.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Input {
+    /// The name of the input in 'flake.nix'.
+    pub name: Box<str>,
+    /// The revision the input is pinned to, if it has one.
+    pub rev: Option<Box<str>>,
+    /// When the pinned revision was last modified.
+    pub last_modified: Option<DateTime<Local>>,
+}
+
+impl FlakeLock {
+    /// Reads the 'flake.lock' file in the given flake directory.
+    pub fn read(flake: &Path) -> Option<Self> {
+        let text = std::fs::read_to_string(flake.join("flake.lock")).ok()?;
+        Self::parse(&text)
+    }
+
+    /// Parses the contents of a 'flake.lock' file.
+    pub fn parse(text: &str) -> Option<Self> {
+        serde_json::from_str(text).ok()
+    }
+
+    /// The direct inputs of the flake, excluding inputs that follow other inputs.
+    pub fn inputs(&self) -> Vec<Input> {
+        let Some(root) = self.nodes.get(&self.root) else {
+            return Vec::new();
+        };
+
+        root.inputs
+            .iter()
+            .filter_map(|(name, input)| match input {
+                InputRef::Node(node) => Some((name, self.nodes.get(node)?)),
+                InputRef::Follows(_) => None,
+            })
+            .map(|(name, node)| {
+                let locked = node.locked.as_ref();
+                Input {
+                    name: name.clone(),
+                    rev: locked.and_then(|locked| locked.rev.clone()),
+                    last_modified: locked
+                        .and_then(|locked| locked.last_modified)
+                        .and_then(|secs| DateTime::from_timestamp(secs, 0))
+                        .map(Into::into),
+                }
+            })
+            .collect()
+    }
+}
+
+/// When inputs pinned in 'flake.lock' are considered stale.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Staleness {
+    /// How many days old a pinned input can be before it's stale.
+    ///
+    /// Inputs are never stale without a threshold.
+    #[serde(default)]
+    pub max_age_days: Option<u32>,
+    /// The inputs to check, such as "nixpkgs", or every direct input if empty.
+    #[serde(default)]
+    pub inputs: Box<[Box<str>]>,
+    /// Whether stale inputs fail switches, instead of only being warned about.
+    #[serde(default)]
+    pub error: bool,
+}
+
+impl Staleness {
+    /// The watched inputs older than the threshold, with their age.
+    ///
+    /// Inputs without a modification time are never stale.
+    pub fn stale<'a>(
+        &self,
+        inputs: &'a [Input],
+        now: DateTime<Local>,
+    ) -> Vec<(&'a Input, TimeDelta)> {
+        let Some(max_age_days) = self.max_age_days else {
+            return Vec::new();
+        };
+        let max_age = TimeDelta::days(max_age_days.into());
+
+        inputs
+            .iter()
+            .filter(|input| self.inputs.is_empty() || self.inputs.contains(&input.name))
+            .filter_map(|input| Some((input, now - input.last_modified?)))
+            .filter(|(_, age)| *age > max_age)
+            .collect()
+    }
+}
+
+/// A direct input whose locked node differs between two lock files.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LockChange {
+    /// The name of the input in 'flake.nix'.
+    pub name: Box<str>,
+    /// The revision in the old lock file, if it has one.
+    pub old_rev: Option<Box<str>>,
+    /// The revision in the new lock file, if it has one.
+    pub new_rev: Option<Box<str>>,
+}
+
+/// The node a direct input of the root refers to, if it refers to one directly.
+fn root_input<'a>(lock: &'a Value, name: &str) -> Option<&'a Value> {
+    let root = lock["root"].as_str()?;
+    let node = lock["nodes"][root]["inputs"][name].as_str()?;
+    lock["nodes"].get(node)
+}
+
+/// The direct inputs that differ between the old & new lock files, in name order.
+///
+/// Inputs only in one of the lock files are included.
+pub fn changes(old: &Value, new: &Value) -> Vec<LockChange> {
+    let names = |lock: &Value| -> Vec<Box<str>> {
+        let root = lock["root"].as_str().unwrap_or("root");
+        lock["nodes"][root]["inputs"]
+            .as_object()
+            .map(|inputs| inputs.keys().map(|name| name.as_str().into()).collect())
+            .unwrap_or_default()
+    };
+    let rev =
+        |node: Option<&Value>| node.and_then(|node| node["locked"]["rev"].as_str().map(Into::into));
+
+    let mut all_names = names(old);
+    all_names.extend(names(new));
+    all_names.sort();
+    all_names.dedup();
+
+    all_names
+        .into_iter()
+        .filter_map(|name| {
+            let old_node = root_input(old, &name);
+            let new_node = root_input(new, &name);
+            let changed =
+                old_node.map(|node| &node["locked"]) != new_node.map(|node| &node["locked"]);
+            changed.then(|| LockChange {
+                old_rev: rev(old_node),
+                new_rev: rev(new_node),
+                name,
+            })
+        })
+        .collect()
+}
+
+/// Creates a lock file from the old lock file, with the given inputs locked as in the new lock file.
+///
+/// The nodes taken from the new lock file are copied under prefixed names, so they can't clash
+/// with the nodes of the old lock file.
+pub fn apply(old: &Value, new: &Value, inputs: &[&LockChange]) -> Value {
+    let mut mixed = old.clone();
+    let root = old["root"].as_str().unwrap_or("root").to_owned();
+
+    for change in inputs {
+        let new_root = new["root"].as_str().unwrap_or("root");
+        match new["nodes"][new_root]["inputs"].get(&*change.name) {
+            Some(Value::String(node)) => {
+                copy_node(new, &mut mixed, node);
+                mixed["nodes"][&root]["inputs"][&*change.name] = Value::String(prefixed(node));
+            }
+            Some(follows) => {
+                mixed["nodes"][&root]["inputs"][&*change.name] = follows.clone();
+            }
+            None => {
+                if let Some(inputs) = mixed["nodes"][&root]["inputs"].as_object_mut() {
+                    inputs.remove(&*change.name);
+                }
+            }
+        }
+    }
+
+    mixed
+}
+
+/// The name a node from the new lock file is copied under.
+fn prefixed(node: &str) -> String {
+    format!("bisect-{node}")
+}
+
+/// Copies the node & the nodes it refers to from one lock file into another, under prefixed names.
+fn copy_node(from: &Value, to: &mut Value, node: &str) {
+    let name = prefixed(node);
+    if to["nodes"].get(&name).is_some() {
+        return;
+    }
+
+    let mut copy = from["nodes"][node].clone();
+    let mut references = Vec::new();
+    if let Some(inputs) = copy.get_mut("inputs").and_then(Value::as_object_mut) {
+        for input in inputs.values_mut() {
+            // Inputs that follow a path from the root are left as is.
+            if let Value::String(reference) = input {
+                references.push(reference.clone());
+                *reference = prefixed(reference);
+            }
+        }
+    }
+
+    to["nodes"][&name] = copy;
+    for reference in references {
+        copy_node(from, to, &reference);
+    }
+}
@@ -1,11 +1,21 @@
-use std::process::ExitCode;
+use std::{io::IsTerminal as _, path::Path, process::ExitCode};
 
 use app_dirs2::AppDataType;
 use camino::Utf8PathBuf;
 use system_manager::{
     APP_INFO, Config, Errors, LOGO,
+    cache::CACHE_FILE,
+    check,
     command_builder::Executer,
+    fleet,
+    generation::{self, GenerationQuery},
+    history::{self, HISTORY_FILE, History},
+    nix::NixInfo,
+    nix_log::BUILD_LOG_DIR,
     options::{self, ConfigPath, Identity, Operation, Task},
+    outdated,
+    status::Status,
+    workflow,
 };
 
 fn main() -> ExitCode {
@@ -17,12 +27,25 @@ fn main() -> ExitCode {
         Task::Command { option } => option,
     };
 
-    if let Err(err) = execute(operation) {
-        eprintln!("Error: {err}");
-        return ExitCode::FAILURE;
-    };
+    match execute(operation) {
+        Ok(()) => ExitCode::SUCCESS,
+        // Declining a switch isn't a failure.
+        Err(err @ Errors::Cancelled) => {
+            eprintln!("{err}");
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            eprintln!("Error: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
 
-    ExitCode::SUCCESS
+/// The path to the given file in the data directory.
+fn data_path(file: &str) -> Result<Box<Path>, Errors> {
+    let mut path = app_dirs2::app_root(AppDataType::UserData, &APP_INFO)?;
+    path.push(file);
+    Ok(path.into_boxed_path())
 }
 
 fn execute(operation: Operation) -> Result<(), Errors> {
@@ -41,7 +64,7 @@ fn execute(operation: Operation) -> Result<(), Errors> {
             Config::parse(config_path.as_ref())?
         } else {
             let config = Config::default();
-            config.write(&config_path.as_ref())?;
+            config.write(config_path.as_ref())?;
             println!(
                 "Set '{}' as path to 'flake.nix' file.\nTo change see 'identity' sub command",
                 config.nix_path
@@ -51,9 +74,172 @@ fn execute(operation: Operation) -> Result<(), Errors> {
     };
 
     match operation {
-        Operation::Switch { switch } => {
-            let executor = Executer::new(switch.display_command, std::io::stdout());
-            system_manager::switch(&config, &switch.targets, switch.update, executor)?;
+        Operation::Switch { mut switch } => {
+            let nix = NixInfo::probe();
+            for warning in nix.warnings() {
+                eprintln!("Warning: {warning}");
+            }
+
+            switch.cache = Some(data_path(CACHE_FILE)?);
+            switch.build_logs = Some(data_path(BUILD_LOG_DIR)?);
+            let mut executor = Executer::new(switch.display_command, std::io::stdout())
+                // The progress is redrawn in place, which only works in a terminal.
+                .with_progress(
+                    (switch.progress || config.progress) && std::io::stdout().is_terminal(),
+                )
+                .with_nom(switch.nom || config.nom);
+            let history = History::new(data_path(HISTORY_FILE)?);
+            if switch.resume {
+                switch = history::resume(&config, &switch, &history)?;
+            }
+            history::recorded_switch(&config, &switch, &nix, &mut executor, &history)?;
+        }
+        Operation::Diff {
+            targets,
+            display_command,
+        } => {
+            let nix = NixInfo::probe();
+            for warning in nix.warnings() {
+                eprintln!("Warning: {warning}");
+            }
+
+            let mut executor = Executer::new(display_command, std::io::stdout());
+            system_manager::diff::diff(&config, &targets, &nix, &mut executor)?;
+        }
+        Operation::Status { json } => {
+            let last_switch = History::new(data_path(HISTORY_FILE)?).last();
+            let status = Status::collect(&config, last_switch);
+
+            if json {
+                println!("{}", serde_json::to_string_pretty(&status)?);
+            } else {
+                print!("{status}");
+            }
+        }
+        Operation::Outdated { json } => {
+            let nix = NixInfo::probe();
+            for warning in nix.warnings() {
+                eprintln!("Warning: {warning}");
+            }
+
+            let mut executor = Executer::new(false, std::io::stderr());
+            let inputs = outdated::check(&config, &nix, &mut executor)?;
+
+            if json {
+                println!("{}", serde_json::to_string_pretty(&inputs)?);
+            } else {
+                print!("{}", outdated::Report(&inputs));
+            }
+        }
+        Operation::History { filter, json } => {
+            let records = History::new(data_path(HISTORY_FILE)?).filter(&filter);
+
+            if json {
+                println!("{}", serde_json::to_string_pretty(&records)?);
+            } else {
+                for record in records {
+                    println!("{record}");
+                }
+            }
+        }
+        Operation::Commit { generation, home } => {
+            let kind = if home { "home-manager" } else { "system" };
+            let number = match generation {
+                GenerationQuery::Number(number) => number,
+                GenerationQuery::Current => {
+                    let profile = if home {
+                        generation::home_profile()
+                    } else {
+                        Some(generation::SYSTEM_PROFILE.into())
+                    };
+                    profile
+                        .and_then(|profile| generation::current(&profile))
+                        .ok_or(Errors::NoActiveGeneration { kind })?
+                        .number
+                }
+            };
+
+            let record = History::new(data_path(HISTORY_FILE)?)
+                .generation(home, number)
+                .ok_or(Errors::UnrecordedGeneration { kind, number })?;
+
+            let commit = record.commit.as_deref().unwrap_or("unknown");
+            let dirty = if record.dirty == Some(true) {
+                " (with uncommitted changes)"
+            } else {
+                ""
+            };
+            println!("Generation {number}: {commit}{dirty}");
+        }
+        Operation::Deploy {
+            identity,
+            display_command,
+            update,
+        } => {
+            let (config, mut switch) = config.deployment(&identity)?;
+            switch.display_command = display_command;
+            switch.update = update;
+
+            let nix = NixInfo::probe();
+            for warning in nix.warnings() {
+                eprintln!("Warning: {warning}");
+            }
+
+            let mut executor = Executer::new(display_command, std::io::stdout());
+            let history = History::new(data_path(HISTORY_FILE)?);
+            history::recorded_switch(&config, &switch, &nix, &mut executor, &history)?;
+        }
+        Operation::Fleet {
+            fleet,
+            display_command,
+        } => {
+            let nix = NixInfo::probe();
+            for warning in nix.warnings() {
+                eprintln!("Warning: {warning}");
+            }
+
+            let mut executor = Executer::new(display_command, std::io::stdout());
+            let history = History::new(data_path(HISTORY_FILE)?);
+            let report = fleet::deploy(&config, &fleet, &nix, &mut executor, Some(&history))?;
+            print!("{report}");
+            report.into_result()?;
+        }
+        Operation::CheckAll { format } => {
+            let nix = NixInfo::probe();
+            for warning in nix.warnings() {
+                eprintln!("Warning: {warning}");
+            }
+
+            // The report is written to stdout, so the output of the commands goes to stderr.
+            let mut executor = Executer::new(false, std::io::stderr()).with_quiet(true);
+            let report = check::check_all(&config, &nix, &mut executor)?;
+            print!("{}", report.format(format)?);
+            report.into_result()?;
+        }
+        Operation::Bisect { bisect } => {
+            let nix = NixInfo::probe();
+            for warning in nix.warnings() {
+                eprintln!("Warning: {warning}");
+            }
+
+            let mut executor = Executer::new(false, std::io::stdout());
+            let change = system_manager::bisect::bisect(&config, &bisect, &nix, &mut executor)?;
+            println!("{}", system_manager::bisect::describe(&change));
+        }
+        Operation::Run {
+            workflow,
+            display_command,
+        } => {
+            let nix = NixInfo::probe();
+            for warning in nix.warnings() {
+                eprintln!("Warning: {warning}");
+            }
+
+            let mut executor = Executer::new(display_command, std::io::stdout());
+            let history = History::new(data_path(HISTORY_FILE)?);
+            let report = workflow::run(&config, &workflow, &nix, &mut executor, Some(&history))?;
+            print!("{report}");
+            report.into_result()?;
         }
         Operation::Identity { operation } => match operation {
             Identity::Get { raw } => {
@@ -0,0 +1,193 @@
+use crate::command_builder::quote;
+use std::process::Command;
+
+/// The oldest nix version that supports flakes.
+const FLAKES_VERSION: Version = Version(2, 4, 0);
+/// The oldest nix version that supports pipe operators.
+const PIPE_OPERATORS_VERSION: Version = Version(2, 24, 0);
+/// The oldest nix version that can write a copy of its log to a file.
+const JSON_LOG_PATH_VERSION: Version = Version(2, 27, 0);
+/// Nix versions older than this are warned about.
+const RECOMMENDED_VERSION: Version = Version(2, 18, 0);
+
+/// A nix version number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version(pub u32, pub u32, pub u32);
+
+impl std::fmt::Display for Version {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}.{}", self.0, self.1, self.2)
+    }
+}
+
+/// The capabilities of the installed nix.
+#[derive(Debug, Clone)]
+pub struct NixInfo {
+    /// The version of nix, if it could be determined.
+    version: Option<Version>,
+    /// Whether nix is the Lix implementation, which names some features differently.
+    lix: bool,
+    /// The experimental features already enabled in the nix config.
+    enabled_features: Box<[Box<str>]>,
+}
+
+impl NixInfo {
+    /// Creates a new [`NixInfo`] from known capabilities.
+    pub fn new(version: Option<Version>, enabled_features: Box<[Box<str>]>) -> Self {
+        Self {
+            version,
+            lix: false,
+            enabled_features,
+        }
+    }
+
+    /// Queries the installed nix for its version & enabled experimental features.
+    ///
+    /// Any part that can't be determined is left unknown rather than erroring.
+    pub fn probe() -> Self {
+        let version_output = run(&["--version"]).unwrap_or_default();
+        let mut info = Self::new(parse_version(&version_output), Box::new([]));
+        info.lix = version_output.contains("Lix");
+
+        let config_output = run(&[
+            "--extra-experimental-features",
+            "nix-command",
+            "config",
+            "show",
+        ])
+        .or_else(|| {
+            run(&[
+                "--extra-experimental-features",
+                "nix-command",
+                "show-config",
+            ])
+        })
+        .unwrap_or_default();
+        info.enabled_features = parse_features(&config_output);
+
+        info
+    }
+
+    /// The version of nix, if it could be determined.
+    pub fn version(&self) -> Option<Version> {
+        self.version
+    }
+
+    /// Whether the installed nix is at least the given version.
+    ///
+    /// An unknown version is assumed to be recent.
+    fn supports(&self, version: Version) -> bool {
+        self.version.is_none_or(|current| current >= version)
+    }
+
+    /// Whether nix can write a copy of its log in the internal-json format to a file, with the
+    /// "json-log-path" setting.
+    pub fn supports_json_log_path(&self) -> bool {
+        !self.lix && self.supports(JSON_LOG_PATH_VERSION)
+    }
+
+    /// The experimental features a flake rebuild requires, that this nix supports.
+    fn required_features(&self) -> Vec<&'static str> {
+        let mut features = vec!["nix-command", "flakes"];
+        if self.lix {
+            features.push("pipe-operator");
+        } else if self.supports(PIPE_OPERATORS_VERSION) {
+            features.push("pipe-operators");
+        }
+        features
+    }
+
+    /// The required experimental features that aren't enabled in the nix config.
+    fn missing_features(&self) -> Vec<&'static str> {
+        self.required_features()
+            .into_iter()
+            .filter(|feature| {
+                !self
+                    .enabled_features
+                    .iter()
+                    .any(|enabled| &**enabled == *feature)
+            })
+            .collect()
+    }
+
+    /// Arguments for enabling missing experimental features on a nix command.
+    pub fn nix_args(&self) -> String {
+        match self.missing_features().as_slice() {
+            [] => String::new(),
+            features => format!(
+                " --extra-experimental-features {}",
+                quote(&features.join(" "))
+            ),
+        }
+    }
+
+    /// Arguments for enabling missing experimental features on a command that wraps nix.
+    ///
+    /// Commands run through sudo don't read the user's nix config, so every required feature is enabled.
+    pub fn wrapper_args(&self, sudo: bool) -> String {
+        let features = if sudo {
+            self.required_features()
+        } else {
+            self.missing_features()
+        };
+
+        match features.as_slice() {
+            [] => String::new(),
+            features => format!(
+                " --option extra-experimental-features {}",
+                quote(&features.join(" "))
+            ),
+        }
+    }
+
+    /// Warnings about the installed nix that the user should know about.
+    pub fn warnings(&self) -> Vec<String> {
+        let Some(version) = self.version else {
+            return vec!["Unable to determine the installed nix version.".into()];
+        };
+
+        if version < FLAKES_VERSION {
+            vec![format!(
+                "Nix {version} does not support flakes. Please upgrade to at least {RECOMMENDED_VERSION}."
+            )]
+        } else if version < RECOMMENDED_VERSION {
+            vec![format!(
+                "Nix {version} is outdated. Please upgrade to at least {RECOMMENDED_VERSION}."
+            )]
+        } else {
+            Vec::new()
+        }
+    }
+}
+
+/// Runs nix with the given arguments, returning stdout if it succeeded.
+fn run(args: &[&str]) -> Option<String> {
+    let output = Command::new("nix").args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    String::from_utf8(output.stdout).ok()
+}
+
+/// Parses the output of "nix --version", such as "nix (Nix) 2.24.10".
+pub(crate) fn parse_version(output: &str) -> Option<Version> {
+    let version = output.split_whitespace().last()?;
+    let mut parts = version
+        .split(['.', '-', 'p'])
+        .map(|part| part.parse::<u32>().ok());
+
+    let major = parts.next()??;
+    let minor = parts.next()??;
+    let patch = parts.next().flatten().unwrap_or(0);
+    Some(Version(major, minor, patch))
+}
+
+/// Parses the enabled experimental features from the output of "nix config show".
+pub(crate) fn parse_features(output: &str) -> Box<[Box<str>]> {
+    output
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .find(|(name, _)| name.trim() == "experimental-features")
+        .map(|(_, value)| value.split_whitespace().map(Into::into).collect())
+        .unwrap_or_default()
+}
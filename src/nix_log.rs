@@ -0,0 +1,374 @@
+use crate::{
+    Errors,
+    command_builder::{CommandError, Executer, quote},
+    nix::NixInfo,
+};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+/// The name of the directory in the data directory holding the build logs of failed derivations.
+pub const BUILD_LOG_DIR: &str = "build-logs";
+
+/// The prefix of each line of nix's internal-json log format.
+const PREFIX: &str = "@nix ";
+
+/// Activity types, as numbered by nix.
+const ACTIVITY_BUILDS: u64 = 104;
+const ACTIVITY_BUILD: u64 = 105;
+const ACTIVITY_SUBSTITUTE: u64 = 108;
+
+/// Result types, as numbered by nix.
+const RESULT_BUILD_LOG_LINE: u64 = 101;
+const RESULT_SET_PHASE: u64 = 104;
+const RESULT_PROGRESS: u64 = 105;
+
+/// The highest verbosity level of messages kept in the log, which is "info".
+const MAX_LOG_LEVEL: u64 = 3;
+
+/// An event of nix's internal-json log.
+#[derive(Deserialize, Debug)]
+#[serde(tag = "action", rename_all = "camelCase")]
+enum Event {
+    Start {
+        id: u64,
+        #[serde(rename = "type")]
+        kind: u64,
+        #[serde(default)]
+        text: Box<str>,
+        #[serde(default)]
+        fields: Vec<serde_json::Value>,
+    },
+    Stop {
+        id: u64,
+    },
+    Result {
+        id: u64,
+        #[serde(rename = "type")]
+        kind: u64,
+        #[serde(default)]
+        fields: Vec<serde_json::Value>,
+    },
+    Msg {
+        level: u64,
+        msg: Box<str>,
+    },
+}
+
+/// A running activity.
+#[derive(Debug)]
+struct Activity {
+    kind: u64,
+    /// The name of what the activity works on, such as a derivation's name.
+    name: Box<str>,
+}
+
+/// The state of a nix command, as followed through its internal-json log.
+#[derive(Debug, Default)]
+pub struct LogState {
+    activities: HashMap<u64, Activity>,
+    builds_done: u64,
+    builds_expected: u64,
+    downloads_done: u64,
+    downloads_started: u64,
+    /// The derivation most recently entering a build phase, with the phase.
+    phase: Option<(Box<str>, Box<str>)>,
+    /// The messages & build output, kept for reporting errors.
+    log: Vec<Box<str>>,
+}
+
+impl LogState {
+    /// Updates the state with a line of the log, returning whether the progress changed.
+    ///
+    /// Lines not in the internal-json format are kept in the log as they are.
+    pub fn handle(&mut self, line: &str) -> bool {
+        let Some(event) = line
+            .strip_prefix(PREFIX)
+            .and_then(|json| serde_json::from_str(json).ok())
+        else {
+            self.log.push(line.into());
+            return false;
+        };
+
+        match event {
+            Event::Start {
+                id,
+                kind,
+                text,
+                fields,
+            } => {
+                if kind == ACTIVITY_SUBSTITUTE {
+                    self.downloads_started += 1;
+                }
+                let name = match fields.first().and_then(serde_json::Value::as_str) {
+                    Some(path) => store_name(path).into(),
+                    None => text,
+                };
+                self.activities.insert(id, Activity { kind, name });
+                matches!(kind, ACTIVITY_BUILD | ACTIVITY_SUBSTITUTE)
+            }
+            Event::Stop { id } => match self.activities.remove(&id) {
+                Some(activity) if activity.kind == ACTIVITY_SUBSTITUTE => {
+                    self.downloads_done += 1;
+                    true
+                }
+                Some(activity) if activity.kind == ACTIVITY_BUILD => {
+                    if self
+                        .phase
+                        .as_ref()
+                        .is_some_and(|(name, _)| *name == activity.name)
+                    {
+                        self.phase = None;
+                    }
+                    true
+                }
+                _ => false,
+            },
+            Event::Result { id, kind, fields } => {
+                let number = |index: usize| fields.get(index).and_then(serde_json::Value::as_u64);
+                let text = |index: usize| fields.get(index).and_then(serde_json::Value::as_str);
+                let activity = self.activities.get(&id);
+
+                match kind {
+                    RESULT_BUILD_LOG_LINE => {
+                        if let Some(line) = text(0) {
+                            self.log.push(line.into());
+                        }
+                        false
+                    }
+                    RESULT_SET_PHASE => {
+                        let (Some(activity), Some(phase)) = (activity, text(0)) else {
+                            return false;
+                        };
+                        self.phase = Some((activity.name.clone(), phase.into()));
+                        true
+                    }
+                    RESULT_PROGRESS
+                        if activity.is_some_and(|activity| activity.kind == ACTIVITY_BUILDS) =>
+                    {
+                        self.builds_done = number(0).unwrap_or(self.builds_done);
+                        self.builds_expected = number(1).unwrap_or(self.builds_expected);
+                        true
+                    }
+                    _ => false,
+                }
+            }
+            Event::Msg { level, msg } => {
+                if level <= MAX_LOG_LEVEL {
+                    self.log.push(msg);
+                }
+                false
+            }
+        }
+    }
+
+    /// Updates the state with an event of the log written to a file, which has no prefix.
+    pub fn handle_event(&mut self, json: &str) -> bool {
+        self.handle(&format!("{PREFIX}{json}"))
+    }
+
+    /// A one line summary of the progress, such as
+    /// "built 3/10, downloaded 5/12, hello-2.12 (buildPhase)".
+    pub fn summary(&self) -> String {
+        let mut summary = format!(
+            "built {}/{}, downloaded {}/{}",
+            self.builds_done, self.builds_expected, self.downloads_done, self.downloads_started
+        );
+
+        if let Some((name, phase)) = &self.phase {
+            summary.push_str(&format!(", {name} ({phase})"));
+        } else if let Some(activity) = self
+            .activities
+            .values()
+            .find(|activity| activity.kind == ACTIVITY_BUILD)
+        {
+            summary.push_str(&format!(", {}", activity.name));
+        }
+
+        summary
+    }
+
+    /// The messages & build output of the command.
+    pub fn log(&self) -> &[Box<str>] {
+        &self.log
+    }
+}
+
+/// The name of a store path, without the store directory & hash.
+fn store_name(path: &str) -> &str {
+    let file = path.rsplit('/').next().unwrap_or(path);
+    let name = file.split_once('-').map_or(file, |(_, name)| name);
+    name.strip_suffix(".drv").unwrap_or(name)
+}
+
+/// The last lines of the log, which usually explain why the command failed.
+pub fn tail(log: &[Box<str>], lines: usize) -> &[Box<str>] {
+    &log[log.len().saturating_sub(lines)..]
+}
+
+/// Converts the failure of a nix command into a more specific error classified from its log, if
+/// possible.
+pub fn failure(err: CommandError, log: &[Box<str>], identity: &str) -> Errors {
+    match err {
+        CommandError::Failed { .. } => classify(log, identity).unwrap_or(err.into()),
+        err => err.into(),
+    }
+}
+
+/// Classifies why a nix command failed from its log, such as an evaluation error or a
+/// derivation failing to build.
+pub fn classify(log: &[Box<str>], identity: &str) -> Option<Errors> {
+    let lines: Vec<_> = log.iter().map(|line| strip_escapes(line)).collect();
+    let lines: Vec<_> = lines.iter().map(|line| line.trim()).collect();
+    let find = |pattern: &str| lines.iter().copied().find(|line| line.contains(pattern));
+    let detail = |line: &str| line.strip_prefix("error:").unwrap_or(line).trim().into();
+
+    if let Some(path) = find("is in the way of").and_then(|line| quoted(line).next()) {
+        return Some(Errors::ClobberedFile { path: path.into() });
+    }
+    if let Some(line) = find("is not tracked by Git").or_else(|| {
+        lines.iter().copied().find(|line| {
+            line.starts_with("error:") && line.contains("Git tree") && line.contains("is dirty")
+        })
+    }) {
+        return Some(Errors::GitTreeNotClean {
+            detail: detail(line),
+        });
+    }
+    if let Some(attribute) = find("does not provide attribute").and_then(|line| quoted(line).last())
+    {
+        return Some(Errors::MissingAttribute {
+            attribute: attribute.into(),
+            identity: identity.into(),
+        });
+    }
+    if let Some(line) = find("unable to download").or_else(|| find("cannot download")) {
+        return Some(Errors::DownloadFailed {
+            detail: detail(line),
+        });
+    }
+    // The derivation that failed itself is named before the ones depending on it.
+    if let Some(derivation) = find("builder for")
+        .or_else(|| find("Cannot build"))
+        .or_else(|| find("dependencies of derivation"))
+        .and_then(|line| quoted(line).find(|text| text.ends_with(".drv")))
+    {
+        return Some(Errors::BuildFailed {
+            derivation: derivation.into(),
+            log: None,
+        });
+    }
+
+    // Evaluation errors are followed by where they happened, after any trace.
+    let error = lines
+        .iter()
+        .rposition(|line| line.starts_with("error:") && line.len() > "error:".len())?;
+    let location = lines[error..]
+        .iter()
+        .chain(lines[..error].iter().rev())
+        .find_map(|line| location(line))?;
+    Some(Errors::EvaluationFailed {
+        location: location.into(),
+        message: detail(lines[error]),
+    })
+}
+
+/// The location of an evaluation error line, such as "at /nix/store/…-source/flake.nix:12:5:",
+/// relative to the flake when it's in the store.
+fn location(line: &str) -> Option<&str> {
+    let location = line.strip_prefix("at ")?.trim_end_matches(':');
+    let (file, position) = location.split_once(':')?;
+    if file.is_empty() || !position.starts_with(|c: char| c.is_ascii_digit()) {
+        return None;
+    }
+
+    let relative = location
+        .strip_prefix("/nix/store/")
+        .and_then(|path| path.split_once('/'))
+        .filter(|(source, _)| source.ends_with("-source"))
+        .map(|(_, path)| path);
+    Some(relative.unwrap_or(location))
+}
+
+/// Prints the last lines of the build log of a failed derivation, saving the full log in the
+/// directory if given.
+///
+/// The error is returned with where the full log is saved, if it could be.
+pub fn show_build_log<T: std::io::Write>(
+    err: Errors,
+    lines: usize,
+    directory: Option<&Path>,
+    nix: &NixInfo,
+    executer: &mut Executer<T>,
+) -> Errors {
+    let Errors::BuildFailed { derivation, .. } = &err else {
+        return err;
+    };
+    let Ok(log) = executer.capture(&format!("nix{} log {}", nix.nix_args(), quote(derivation)))
+    else {
+        return err;
+    };
+    if executer.is_display() {
+        return err;
+    }
+
+    // The build failure is more important to report than its log.
+    let _ = executer.report(&build_log_tail(derivation, &log, lines));
+    match directory.map(|directory| save_build_log(derivation, &log, directory)) {
+        Some(Ok(path)) => Errors::BuildFailed {
+            derivation: derivation.clone(),
+            log: Some(path.into_boxed_path()),
+        },
+        _ => err,
+    }
+}
+
+/// Describes the last lines of the build log of a derivation.
+pub(crate) fn build_log_tail(derivation: &str, log: &str, lines: usize) -> String {
+    let all: Vec<_> = log.lines().collect();
+    let last = &all[all.len().saturating_sub(lines)..];
+    format!(
+        "Last {} lines of the build log of '{derivation}':\n{}",
+        last.len(),
+        last.join("\n")
+    )
+}
+
+/// Saves the full build log of a derivation in the directory, returning the path it's saved to.
+pub(crate) fn save_build_log(
+    derivation: &str,
+    log: &str,
+    directory: &Path,
+) -> Result<PathBuf, Errors> {
+    let file = derivation.rsplit('/').next().unwrap_or(derivation);
+    let path = directory.join(format!("{}.log", file.trim_end_matches(".drv")));
+    let error = || Errors::DataWrite {
+        path: path.clone().into_boxed_path(),
+    };
+    std::fs::create_dir_all(directory).map_err(|_| error())?;
+    std::fs::write(&path, format!("{log}\n")).map_err(|_| error())?;
+
+    Ok(path)
+}
+
+/// The quoted parts of a line, with either plain or typographic quotes.
+fn quoted(line: &str) -> impl Iterator<Item = &str> {
+    line.split(['\'', '‘', '’']).skip(1).step_by(2)
+}
+
+/// Removes ANSI escape sequences, which nix uses to colour its messages.
+pub(crate) fn strip_escapes(line: &str) -> String {
+    let mut text = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // Skips to the final letter of the sequence.
+            chars.by_ref().find(char::is_ascii_alphabetic);
+        } else {
+            text.push(c);
+        }
+    }
+    text
+}
@@ -1,12 +1,57 @@
-use crate::options::parsed::{CLIArgs, IdentityOptions, PathOption, SwitchArgs, SwitchTarget};
+use crate::options::parsed::{
+    CLIArgs, DiffTarget, IdentityOptions, PathOption, SwitchArgs, SwitchTarget,
+};
+use crate::{
+    bisect::Bisect, check::Format, fleet::Fleet, generation::GenerationQuery, history::Filter,
+};
 use camino::Utf8Path;
-use clap::{CommandFactory as _, Parser};
+use clap::{CommandFactory as _, Parser, error::ErrorKind};
 use clap_complete::Shell;
+use std::{ffi::OsString, path::Path};
 
 mod parsed;
 
 pub fn parse() -> Task {
-    CLIArgs::parse().into()
+    try_parse_from(std::env::args_os()).unwrap_or_else(|err| err.exit())
+}
+
+/// Parses the given command line arguments, starting with the program name.
+pub(crate) fn try_parse_from<I, T>(args: I) -> Result<Task, clap::Error>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<OsString> + Clone,
+{
+    CLIArgs::try_parse_from(args)?.try_into()
+}
+
+/// An error in the arguments of the switch sub command.
+fn switch_error(kind: ErrorKind, message: impl std::fmt::Display) -> clap::Error {
+    let mut command = CLIArgs::command();
+    command.build();
+    command
+        .find_subcommand_mut("switch")
+        .expect("The switch sub command exists.")
+        .error(kind, message)
+}
+
+/// Whether the argument is one of the switch sub command's flags, which apply to every target.
+fn is_switch_flag(arg: &str) -> bool {
+    let command = CLIArgs::command();
+    let switch = command
+        .find_subcommand("switch")
+        .expect("The switch sub command exists.");
+
+    switch
+        .get_arguments()
+        .filter(|flag| flag.is_global_set())
+        .any(|flag| {
+            flag.get_long().is_some_and(|long| {
+                arg.strip_prefix("--")
+                    .is_some_and(|arg| arg.split('=').next() == Some(long))
+            }) || flag
+                .get_short()
+                .is_some_and(|short| arg == format!("-{short}"))
+        })
 }
 
 pub fn completions(shell: Shell) {
@@ -26,6 +71,58 @@ pub enum Task {
 pub enum Operation {
     /// Rebuild and switch the system with the current identity.
     Switch { switch: Switch },
+    /// Shows the changes a switch would make, without activating anything.
+    Diff {
+        targets: Box<[ToSwitch]>,
+        /// Display the commands instead of executing them.
+        display_command: bool,
+    },
+    /// Shows an overview of the system & its nix configuration.
+    Status {
+        /// Output the status as JSON.
+        json: bool,
+    },
+    /// Lists the inputs that have newer revisions than those pinned.
+    Outdated {
+        /// Output the inputs as JSON.
+        json: bool,
+    },
+    /// Lists the switches that have been run.
+    History {
+        filter: Filter,
+        /// Output the switches as JSON.
+        json: bool,
+    },
+    /// Shows the commit of the nix configuration that built a generation.
+    Commit {
+        generation: GenerationQuery,
+        /// Look up a home-manager generation instead of a system generation.
+        home: bool,
+    },
+    /// Deploys the system of an identity to its host.
+    Deploy {
+        identity: Box<str>,
+        /// Display the commands instead of executing them.
+        display_command: bool,
+        /// Update the 'flake.lock' file before deploying.
+        update: bool,
+    },
+    /// Deploys several identities to their hosts.
+    Fleet {
+        fleet: Fleet,
+        /// Display the commands instead of executing them.
+        display_command: bool,
+    },
+    /// Builds every configuration in the flake & runs "nix flake check".
+    CheckAll { format: Format },
+    /// Finds the 'flake.lock' input update that broke a build.
+    Bisect { bisect: Bisect },
+    /// Runs a workflow defined in the config.
+    Run {
+        workflow: Box<str>,
+        /// Display the commands instead of executing them.
+        display_command: bool,
+    },
     /// The identity of the nix configuration to use.
     Identity { operation: Identity },
     /// The path to the nix configuration.
@@ -35,17 +132,74 @@ pub enum Operation {
 }
 
 /// Switch configuration.
+#[derive(Default, Clone)]
 pub struct Switch {
     pub targets: Box<[ToSwitch]>,
 
+    /// Switch every target in the order set in the config, instead of `targets`.
+    pub all: bool,
+
     /// Display the switch commands instead of executing them.
     pub display_command: bool,
 
     /// Update the 'flake.lock' file as well as rebuilding the system.
     pub update: bool,
+
+    /// Show the changes each target would make before activating it.
+    pub diff: bool,
+
+    /// Ask for confirmation of the switch plan before activating.
+    pub confirm: bool,
+
+    /// Skip any confirmation of the switch plan.
+    pub yes: bool,
+
+    /// Extra arguments passed through to every switch command.
+    pub extra_args: Box<[Box<str>]>,
+
+    /// Rebuild every target, even those that are up to date.
+    pub force: bool,
+
+    /// Build every target before activating any of them.
+    pub build_first: bool,
+
+    /// Check & evaluate the flake before asking for sudo.
+    pub preflight: bool,
+
+    /// Render concise progress of nix builds, instead of their full output.
+    pub progress: bool,
+
+    /// Show nix builds with nix-output-monitor, when it's installed.
+    pub nom: bool,
+
+    /// Only run the update & targets that didn't complete in the last failed switch.
+    pub resume: bool,
+
+    /// Resume the last failed switch, even if the nix configuration changed since.
+    pub ignore_changes: bool,
+
+    /// The SSH address to activate the system on, instead of the local machine.
+    pub target_host: Option<Box<str>>,
+
+    /// The SSH address to build the system on, instead of the local machine.
+    pub build_host: Option<Box<str>>,
+
+    /// Activate with sudo on the target host.
+    pub use_remote_sudo: bool,
+
+    /// The file caching what previous switches built, to skip targets that are up to date.
+    ///
+    /// Every target is rebuilt without one.
+    pub cache: Option<Box<Path>>,
+
+    /// The directory the build logs of failed derivations are saved in.
+    ///
+    /// The logs are only printed without one.
+    pub build_logs: Option<Box<Path>>,
 }
 
 /// Target to switch.
+#[derive(Clone, Debug)]
 pub enum ToSwitch {
     /// Perform a home-manager switch.
     Home,
@@ -54,6 +208,28 @@ pub enum ToSwitch {
         /// Switch system without downloading any more data.
         offline: bool,
     },
+    /// Perform a switch of a target defined in the config.
+    Custom { name: Box<str> },
+}
+
+impl ToSwitch {
+    /// Gets the target with the given name, treating unknown names as custom targets.
+    pub fn from_name(name: &str) -> Self {
+        match name {
+            "system" => ToSwitch::System { offline: false },
+            "home" => ToSwitch::Home,
+            name => ToSwitch::Custom { name: name.into() },
+        }
+    }
+
+    /// The name of the target, as given on the command line.
+    pub fn name(&self) -> &str {
+        match self {
+            ToSwitch::Home => "home",
+            ToSwitch::System { .. } => "system",
+            ToSwitch::Custom { name } => name,
+        }
+    }
 }
 
 /// Which identity operation to perform.
@@ -78,12 +254,121 @@ pub enum ConfigPath {
     },
 }
 
-impl From<CLIArgs> for Task {
-    fn from(value: CLIArgs) -> Self {
-        match value {
+impl TryFrom<CLIArgs> for Task {
+    type Error = clap::Error;
+
+    fn try_from(value: CLIArgs) -> Result<Self, Self::Error> {
+        Ok(match value {
+            CLIArgs::Switch { args } if args.target.is_some() == args.resume => {
+                return Err(if args.resume {
+                    switch_error(
+                        ErrorKind::ArgumentConflict,
+                        "A target can't be given when resuming a switch.",
+                    )
+                } else {
+                    switch_error(
+                        ErrorKind::MissingSubcommand,
+                        "A target to switch is required, unless resuming with '--resume'.",
+                    )
+                });
+            }
             CLIArgs::Switch { args } => Task::Command {
                 option: Operation::Switch {
-                    switch: args.into(),
+                    switch: args.try_into()?,
+                },
+            },
+            CLIArgs::Diff {
+                target,
+                display_command,
+            } => Task::Command {
+                option: Operation::Diff {
+                    targets: target.into(),
+                    display_command,
+                },
+            },
+            CLIArgs::Status { json } => Task::Command {
+                option: Operation::Status { json },
+            },
+            CLIArgs::Outdated { json } => Task::Command {
+                option: Operation::Outdated { json },
+            },
+            CLIArgs::History {
+                identity,
+                target,
+                outcome,
+                limit,
+                json,
+            } => Task::Command {
+                option: Operation::History {
+                    filter: Filter {
+                        identity: identity.map(Into::into),
+                        target: target.map(Into::into),
+                        outcome,
+                        limit: Some(limit),
+                    },
+                    json,
+                },
+            },
+            CLIArgs::Commit { generation, home } => Task::Command {
+                option: Operation::Commit { generation, home },
+            },
+            CLIArgs::Deploy {
+                identity,
+                display_command,
+                update,
+            } => Task::Command {
+                option: Operation::Deploy {
+                    identity: identity.into(),
+                    display_command,
+                    update,
+                },
+            },
+            CLIArgs::Fleet {
+                members,
+                jobs,
+                canary,
+                fail_fast,
+                display_command,
+            } => Task::Command {
+                option: Operation::Fleet {
+                    fleet: Fleet {
+                        members: members.into_iter().map(Into::into).collect(),
+                        jobs,
+                        canary,
+                        fail_fast,
+                    },
+                    display_command,
+                },
+            },
+            CLIArgs::CheckAll { format } => Task::Command {
+                option: Operation::CheckAll { format },
+            },
+            CLIArgs::Bisect {
+                good,
+                bad,
+                command,
+                home,
+            } => Task::Command {
+                option: Operation::Bisect {
+                    bisect: Bisect {
+                        good: good.into(),
+                        bad: bad.map(Into::into),
+                        target: if home {
+                            ToSwitch::Home
+                        } else {
+                            ToSwitch::System { offline: false }
+                        },
+                        command: command.map(Into::into),
+                    },
+                },
+            },
+            CLIArgs::Run {
+                workflow,
+                display_command,
+            } => Task::Command {
+                option: Operation::Run {
+                    workflow: workflow.into(),
+                    display_command,
                 },
             },
             CLIArgs::Identity { operation } => Task::Command {
@@ -100,27 +385,98 @@ impl From<CLIArgs> for Task {
                 option: Operation::Logo,
             },
             CLIArgs::Completions { shell } => Task::Completion { shell },
-        }
+        })
     }
 }
 
-impl From<SwitchArgs> for Switch {
-    fn from(value: SwitchArgs) -> Self {
+impl TryFrom<SwitchArgs> for Switch {
+    type Error = clap::Error;
+
+    fn try_from(value: SwitchArgs) -> Result<Self, Self::Error> {
         let mut targets = Vec::new();
+        let mut all = false;
+        let (mut target_host, mut build_host, mut use_remote_sudo) = (None, None, false);
 
-        match value.target {
-            SwitchTarget::Home => targets.push(ToSwitch::Home),
-            SwitchTarget::System { offline } => targets.push(ToSwitch::System { offline }),
-            SwitchTarget::Both => {
+        let extra_args = match value.target {
+            None => Vec::new(),
+            Some(SwitchTarget::Home { passthrough }) => {
+                targets.push(ToSwitch::Home);
+                passthrough.extra_args
+            }
+            Some(SwitchTarget::System {
+                offline,
+                remote,
+                passthrough,
+            }) => {
+                targets.push(ToSwitch::System { offline });
+                target_host = remote.target_host.map(Into::into);
+                build_host = remote.build_host.map(Into::into);
+                use_remote_sudo = remote.use_remote_sudo;
+                passthrough.extra_args
+            }
+            Some(SwitchTarget::Both { passthrough }) => {
                 targets.push(ToSwitch::System { offline: false });
                 targets.push(ToSwitch::Home);
+                passthrough.extra_args
+            }
+            Some(SwitchTarget::All { passthrough }) => {
+                all = true;
+                passthrough.extra_args
+            }
+            Some(SwitchTarget::Custom(mut args)) => {
+                if !args.is_empty() {
+                    let name = args.remove(0);
+                    targets.push(ToSwitch::Custom { name: name.into() });
+                }
+
+                // Switch flags after the name would silently be passed through instead.
+                let (before, after) = match args.iter().position(|arg| arg == "--") {
+                    Some(index) => (&args[..index], &args[index + 1..]),
+                    None => (&args[..], &[][..]),
+                };
+                if let Some(flag) = before.iter().find(|arg| is_switch_flag(arg)) {
+                    return Err(switch_error(
+                        ErrorKind::ArgumentConflict,
+                        format!(
+                            "'{flag}' must be given before the target name, as arguments after it are passed through to the target's command."
+                        ),
+                    ));
+                }
+                before.iter().chain(after).cloned().collect()
             }
         };
 
-        Self {
+        Ok(Self {
             targets: targets.into_boxed_slice(),
+            all,
             display_command: value.display_command,
             update: value.update,
+            diff: value.diff,
+            confirm: value.confirm,
+            yes: value.yes,
+            extra_args: extra_args.into_iter().map(Into::into).collect(),
+            force: value.force,
+            build_first: value.build_first,
+            preflight: value.preflight,
+            progress: value.progress,
+            nom: value.nom,
+            resume: value.resume,
+            ignore_changes: value.ignore_changes,
+            target_host,
+            build_host,
+            use_remote_sudo,
+            cache: None,
+            build_logs: None,
+        })
+    }
+}
+
+impl From<DiffTarget> for Box<[ToSwitch]> {
+    fn from(value: DiffTarget) -> Self {
+        match value {
+            DiffTarget::Home => Box::new([ToSwitch::Home]),
+            DiffTarget::System => Box::new([ToSwitch::System { offline: false }]),
+            DiffTarget::Both => Box::new([ToSwitch::System { offline: false }, ToSwitch::Home]),
         }
     }
 }
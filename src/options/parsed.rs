@@ -1,3 +1,4 @@
+use crate::{check::Format, generation::GenerationQuery, history::Outcome};
 use camino::Utf8Path;
 use clap::{Parser, Subcommand};
 use clap_complete::Shell;
@@ -13,6 +14,143 @@ pub(crate) enum CLIArgs {
         #[command(flatten)]
         args: SwitchArgs,
     },
+    /// Shows the changes a switch would make, without activating anything.
+    ///
+    /// The target is built & its closure is compared against the currently active generation.
+    Diff {
+        #[command(subcommand)]
+        target: DiffTarget,
+
+        /// Display the shell commands instead of executing them.
+        #[arg(long = "display", global = true)]
+        display_command: bool,
+    },
+    /// Shows an overview of the system & its nix configuration.
+    Status {
+        /// Output the status as JSON.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Lists the inputs that have newer revisions than those pinned in 'flake.lock'.
+    ///
+    /// The latest revisions are resolved into a temporary lock file, so 'flake.lock' is left
+    /// untouched.
+    Outdated {
+        /// Output the inputs as JSON.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Lists the switches that have been run, oldest first.
+    History {
+        /// Only list switches of this identity.
+        #[arg(long)]
+        identity: Option<String>,
+        /// Only list switches that included this target.
+        #[arg(long)]
+        target: Option<String>,
+        /// Only list switches with this outcome.
+        #[arg(long)]
+        outcome: Option<Outcome>,
+        /// The maximum number of switches to list.
+        #[arg(long, short = 'n', default_value_t = 20)]
+        limit: usize,
+        /// Output the switches as JSON.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Shows the commit of the nix configuration that built a generation.
+    ///
+    /// Only generations built by a recorded switch can be looked up.
+    Commit {
+        /// The generation number, or "current" for the active generation.
+        #[arg(default_value = "current")]
+        generation: GenerationQuery,
+        /// Look up a home-manager generation instead of a system generation.
+        #[arg(long)]
+        home: bool,
+    },
+    /// Deploys the system of an identity to its host over SSH.
+    ///
+    /// The host is set per identity in the config.
+    Deploy {
+        /// The identity to deploy.
+        identity: String,
+
+        /// Display the shell commands instead of executing them.
+        #[arg(long = "display")]
+        display_command: bool,
+
+        /// Update the 'flake.lock' file before deploying.
+        #[arg(long)]
+        update: bool,
+    },
+    /// Deploys several identities to their hosts in one run.
+    ///
+    /// Every system is built first, then activated one host at a time.
+    Fleet {
+        /// The identities & groups to deploy, or every configured host if none are given.
+        members: Vec<String>,
+
+        /// How many systems to build at once.
+        #[arg(long, short, default_value_t = 4)]
+        jobs: usize,
+
+        /// Build & activate the first identity on its own, stopping if it fails.
+        #[arg(long)]
+        canary: bool,
+
+        /// Stop at the first failure, instead of continuing with the other hosts.
+        #[arg(long)]
+        fail_fast: bool,
+
+        /// Display the shell commands instead of executing them.
+        #[arg(long = "display")]
+        display_command: bool,
+    },
+    /// Builds every system & home-manager configuration in the flake, then runs
+    /// "nix flake check".
+    ///
+    /// Nothing is activated & sudo is never needed, so this is suitable for CI.
+    /// A report of the checks is written to stdout.
+    CheckAll {
+        /// The format of the report.
+        #[arg(long, value_enum, default_value_t = Format::Json)]
+        format: Format,
+    },
+    /// Finds the 'flake.lock' input update that broke a build.
+    ///
+    /// The inputs changed between the good & bad lock files are bisected, building the system
+    /// (or home-manager) configuration with a subset of the updates at each step.
+    Bisect {
+        /// The lock file the build works with, as a path or "git:<rev>".
+        #[arg(long)]
+        good: String,
+        /// The lock file the build is broken with, as a path or "git:<rev>".
+        ///
+        /// Defaults to the current 'flake.lock' file.
+        #[arg(long)]
+        bad: Option<String>,
+        /// A shell command deciding whether each build is good, by exiting successfully.
+        ///
+        /// The build's store path is set in "SYSTEM_MANAGER_RESULT". Without a command, you're
+        /// asked whether each build is good.
+        #[arg(long)]
+        command: Option<String>,
+        /// Bisect the home-manager configuration instead of the system configuration.
+        #[arg(long)]
+        home: bool,
+    },
+    /// Runs a workflow defined in the config.
+    ///
+    /// Each step of the workflow is run in order, stopping at the first failure.
+    Run {
+        /// The name of the workflow to run.
+        workflow: String,
+
+        /// Display the shell commands instead of executing them.
+        #[arg(long = "display")]
+        display_command: bool,
+    },
     /// The identity of the nix configuration to use.
     ///
     /// This determines which flake "#___" will be used when rebuilding the system.
@@ -62,8 +200,9 @@ pub(crate) enum PathOption {
 
 #[derive(Clone, Debug, clap::Args)]
 pub(crate) struct SwitchArgs {
+    /// The target to switch, which is required unless resuming.
     #[command(subcommand)]
-    pub(crate) target: SwitchTarget,
+    pub(crate) target: Option<SwitchTarget>,
 
     /// Display the shell switch commands instead of executing them.
     ///
@@ -75,18 +214,125 @@ pub(crate) struct SwitchArgs {
     /// Update the 'flake.lock' file as well as rebuilding the system.
     #[arg(long, global = true)]
     pub(crate) update: bool,
+
+    /// Show the changes each target would make before activating it.
+    #[arg(long, global = true)]
+    pub(crate) diff: bool,
+
+    /// Show the switch plan & ask for confirmation before activating.
+    #[arg(long, global = true, conflicts_with = "yes")]
+    pub(crate) confirm: bool,
+
+    /// Skip confirmation of the switch plan, even if it's required in the config.
+    #[arg(long, short, global = true)]
+    pub(crate) yes: bool,
+
+    /// Rebuild every target, even if nothing changed since it was last switched.
+    #[arg(long, global = true)]
+    pub(crate) force: bool,
+
+    /// Build every target before activating any, so a failed build leaves nothing activated.
+    #[arg(long, global = true)]
+    pub(crate) build_first: bool,
+
+    /// Check the flake & evaluate each target before asking for sudo, so evaluation errors
+    /// show up immediately.
+    #[arg(long, global = true)]
+    pub(crate) preflight: bool,
+
+    /// Render concise progress of nix builds, such as how many derivations are built, instead
+    /// of their full output.
+    ///
+    /// The full output is still shown if a build fails, or when not writing to a terminal.
+    #[arg(long, global = true)]
+    pub(crate) progress: bool,
+
+    /// Show nix builds with nix-output-monitor ("nom"), when it's installed.
+    ///
+    /// Without it, the builds are shown as usual.
+    #[arg(long, global = true)]
+    pub(crate) nom: bool,
+
+    /// Only run the update & targets that didn't complete in the last failed switch.
+    ///
+    /// This is refused if the nix configuration changed since, unless "--ignore-changes" is
+    /// given.
+    #[arg(long)]
+    pub(crate) resume: bool,
+
+    /// Resume the last failed switch, even if the nix configuration changed since.
+    #[arg(long, requires = "resume")]
+    pub(crate) ignore_changes: bool,
+}
+
+#[derive(Clone, Debug, clap::Args)]
+pub(crate) struct Passthrough {
+    /// Extra arguments passed through to every switch command.
+    ///
+    /// These are appended after any arguments set in the config.
+    #[arg(last = true)]
+    pub(crate) extra_args: Vec<String>,
+}
+
+/// Where a system switch is built & activated, when it isn't the local machine.
+#[derive(Clone, Debug, clap::Args)]
+pub(crate) struct Remote {
+    /// Activate the system on this host over SSH, such as "root@example.com".
+    #[arg(long)]
+    pub(crate) target_host: Option<String>,
+
+    /// Build the system on this host over SSH, instead of locally.
+    #[arg(long)]
+    pub(crate) build_host: Option<String>,
+
+    /// Activate with sudo on the target host, for SSH users other than root.
+    #[arg(long, requires = "target_host")]
+    pub(crate) use_remote_sudo: bool,
 }
 
 #[derive(Clone, Debug, Subcommand)]
 pub(crate) enum SwitchTarget {
     /// Perform a home-manager switch.
-    Home,
+    Home {
+        #[command(flatten)]
+        passthrough: Passthrough,
+    },
     /// Perform a system switch.
     System {
         /// Switch system without downloading any more data.
         #[arg(long, global = true)]
         offline: bool,
+
+        #[command(flatten)]
+        remote: Remote,
+
+        #[command(flatten)]
+        passthrough: Passthrough,
     },
     /// Switches the system and then home-manager.
+    Both {
+        #[command(flatten)]
+        passthrough: Passthrough,
+    },
+    /// Switches every target in the order set in the config.
+    All {
+        #[command(flatten)]
+        passthrough: Passthrough,
+    },
+    /// Perform a switch of a target defined in the config.
+    ///
+    /// Any arguments after the target name are passed through to its command, so switch flags
+    /// need to be given before it.
+    #[command(external_subcommand)]
+    Custom(Vec<String>),
+}
+
+#[derive(Clone, Debug, Subcommand)]
+pub(crate) enum DiffTarget {
+    /// Show the changes of a home-manager switch.
+    Home,
+    /// Show the changes of a system switch.
+    System,
+    /// Show the changes of both a system & home-manager switch.
     Both,
 }
@@ -0,0 +1,133 @@
+use crate::{
+    Config, Errors,
+    command_builder::{Execute as _, Executer, quote},
+    history::short_hash,
+    lock::FlakeLock,
+    nix::NixInfo,
+    status::format_age,
+};
+use chrono::{DateTime, Local};
+use serde::Serialize;
+use std::{fmt::Display, path::Path};
+
+/// A direct input of the flake, as pinned & as it would be locked by an update.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct InputState {
+    /// The name of the input in 'flake.nix'.
+    pub name: Box<str>,
+    /// The revision pinned in 'flake.lock', if it has one.
+    pub pinned_rev: Option<Box<str>>,
+    /// When the pinned revision was last modified.
+    pub pinned_modified: Option<DateTime<Local>>,
+    /// The latest revision, if the input still exists & has one.
+    pub latest_rev: Option<Box<str>>,
+    /// When the latest revision was last modified.
+    pub latest_modified: Option<DateTime<Local>>,
+}
+
+impl InputState {
+    /// Whether an update would lock the input to something newer.
+    pub fn is_outdated(&self) -> bool {
+        self.pinned_rev != self.latest_rev || self.pinned_modified != self.latest_modified
+    }
+}
+
+/// Pairs each input pinned in the lock file with the same input in an updated lock file.
+pub fn compare(pinned: &FlakeLock, latest: &FlakeLock) -> Vec<InputState> {
+    let latest = latest.inputs();
+
+    pinned
+        .inputs()
+        .into_iter()
+        .map(|input| {
+            let updated = latest.iter().find(|updated| updated.name == input.name);
+            InputState {
+                latest_rev: updated.and_then(|updated| updated.rev.clone()),
+                latest_modified: updated.and_then(|updated| updated.last_modified),
+                name: input.name,
+                pinned_rev: input.rev,
+                pinned_modified: input.last_modified,
+            }
+        })
+        .collect()
+}
+
+/// Resolves the latest revision of every input into a temporary lock file, then compares it
+/// with 'flake.lock', which is left untouched.
+pub fn check<T: std::io::Write>(
+    config: &Config,
+    nix: &NixInfo,
+    executer: &mut Executer<T>,
+) -> Result<Vec<InputState>, Errors> {
+    let flake = config.nix_path.as_std_path();
+    let pinned = read(&flake.join("flake.lock"))?;
+
+    let lock_path = std::env::temp_dir().join(format!(
+        "system-manager-outdated-{}.lock",
+        std::process::id()
+    ));
+    executer.execute(&format!(
+        "nix{} flake update --flake {} --output-lock-file {}",
+        nix.nix_args(),
+        quote(config.nix_path.as_str()),
+        quote(&lock_path.to_string_lossy())
+    ))?;
+
+    let latest = read(&lock_path);
+    let _ = std::fs::remove_file(&lock_path);
+
+    Ok(compare(&pinned, &latest?))
+}
+
+/// Reads & parses the lock file at the given path.
+fn read(path: &Path) -> Result<FlakeLock, Errors> {
+    std::fs::read_to_string(path)
+        .ok()
+        .and_then(|text| FlakeLock::parse(&text))
+        .ok_or_else(|| Errors::LockRead {
+            reference: path.to_string_lossy().into(),
+        })
+}
+
+/// The inputs of a flake, for reporting which ones are outdated.
+pub struct Report<'a>(pub &'a [InputState]);
+
+impl Display for Report<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let now = Local::now();
+        let age = |modified: &Option<DateTime<Local>>| {
+            modified
+                .map(|modified| format!("{} old", format_age(now - modified)))
+                .unwrap_or_else(|| "unknown age".into())
+        };
+
+        for input in self.0 {
+            if input.is_outdated() {
+                writeln!(
+                    f,
+                    "{}: {} -> {} (pinned {}, latest {})",
+                    input.name,
+                    rev(&input.pinned_rev),
+                    rev(&input.latest_rev),
+                    age(&input.pinned_modified),
+                    age(&input.latest_modified)
+                )?;
+            } else {
+                writeln!(
+                    f,
+                    "{}: up to date ({}, {})",
+                    input.name,
+                    rev(&input.pinned_rev),
+                    age(&input.pinned_modified)
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The short form of the revision, if there is one.
+fn rev(rev: &Option<Box<str>>) -> &str {
+    rev.as_deref().map(short_hash).unwrap_or("unknown")
+}
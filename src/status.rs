@@ -0,0 +1,152 @@
+use crate::{
+    Config,
+    generation::{self, Generation},
+    git,
+    history::SwitchRecord,
+    lock::{FlakeLock, Input},
+};
+use camino::Utf8Path;
+use chrono::{DateTime, Local, TimeDelta};
+use serde::Serialize;
+use std::{fmt::Display, path::Path};
+
+/// An overview of the state of the system & its nix configuration.
+#[derive(Serialize, Debug)]
+pub struct Status {
+    pub identity: Box<str>,
+    pub flake_path: Box<Utf8Path>,
+    /// The active system generation, if it could be determined.
+    pub system: Option<Generation>,
+    /// The active home-manager generation, if it could be determined.
+    pub home: Option<Generation>,
+    /// The direct inputs pinned in 'flake.lock'.
+    pub inputs: Vec<Input>,
+    /// The names of the inputs older than the configured threshold.
+    pub stale_inputs: Vec<Box<str>>,
+    /// The state of the git repository holding the nix configuration.
+    pub git: Option<GitStatus>,
+    /// The last switch that was run.
+    pub last_switch: Option<SwitchRecord>,
+}
+
+/// The state of a git repository.
+#[derive(Serialize, Debug)]
+pub struct GitStatus {
+    pub branch: Box<str>,
+    pub dirty: bool,
+}
+
+impl Status {
+    /// Collects the status of the system with the given config.
+    pub fn collect(config: &Config, last_switch: Option<SwitchRecord>) -> Self {
+        let flake = config.nix_path.as_std_path();
+        let inputs = FlakeLock::read(flake)
+            .map(|lock| lock.inputs())
+            .unwrap_or_default();
+
+        Self {
+            identity: config.identity.clone(),
+            flake_path: config.nix_path.clone(),
+            system: generation::current(Path::new(generation::SYSTEM_PROFILE)),
+            home: generation::home_profile().and_then(|profile| generation::current(&profile)),
+            stale_inputs: config
+                .stale_lock
+                .stale(&inputs, Local::now())
+                .into_iter()
+                .map(|(input, _)| input.name.clone())
+                .collect(),
+            inputs,
+            git: git::branch(flake).map(|branch| GitStatus {
+                branch: branch.into(),
+                dirty: git::is_dirty(flake).unwrap_or(false),
+            }),
+            last_switch,
+        }
+    }
+}
+
+impl Display for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let now = Local::now();
+        let generation = |generation: &Option<Generation>| match generation {
+            Some(generation) => format!(
+                "{} (built {})",
+                generation.number,
+                format_time(generation.built, now)
+            ),
+            None => "unknown".into(),
+        };
+
+        writeln!(f, "Identity: {}", self.identity)?;
+        writeln!(f, "Nix Path: {}", self.flake_path)?;
+        writeln!(f, "System generation: {}", generation(&self.system))?;
+        writeln!(f, "Home generation: {}", generation(&self.home))?;
+
+        match &self.git {
+            Some(git) => {
+                let state = if git.dirty { "dirty" } else { "clean" };
+                writeln!(f, "Git: {} ({state})", git.branch)?;
+            }
+            None => writeln!(f, "Git: not a repository")?,
+        }
+
+        match &self.last_switch {
+            Some(record) => writeln!(
+                f,
+                "Last switch: {} {} ({})",
+                record.targets.join(", "),
+                record.outcome,
+                format_time(record.time, now)
+            )?,
+            None => writeln!(f, "Last switch: unknown")?,
+        }
+
+        writeln!(f, "Inputs:")?;
+        for input in &self.inputs {
+            let age = input
+                .last_modified
+                .map(|modified| format_age(now - modified))
+                .unwrap_or_else(|| "unknown age".into());
+            let stale = if self.stale_inputs.contains(&input.name) {
+                " (stale)"
+            } else {
+                ""
+            };
+            writeln!(f, "  {}: {age}{stale}", input.name)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Formats the time as a date, followed by how long ago it was.
+fn format_time(time: DateTime<Local>, now: DateTime<Local>) -> String {
+    format!(
+        "{}, {} ago",
+        time.format("%Y-%m-%d %H:%M"),
+        format_age(now - time)
+    )
+}
+
+/// Formats the age in its largest whole unit, such as "3 days".
+pub fn format_age(age: TimeDelta) -> String {
+    let (amount, unit) = if age.num_days() > 0 {
+        (age.num_days(), "day")
+    } else if age.num_hours() > 0 {
+        (age.num_hours(), "hour")
+    } else {
+        (age.num_minutes().max(0), "minute")
+    };
+
+    let plural = if amount == 1 { "" } else { "s" };
+    format!("{amount} {unit}{plural}")
+}
+
+/// Describes the stale inputs & their ages, such as "nixpkgs (40 days)".
+pub fn describe_stale(stale: &[(&Input, TimeDelta)]) -> String {
+    stale
+        .iter()
+        .map(|(input, age)| format!("{} ({})", input.name, format_age(*age)))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
@@ -1,6 +1,86 @@
 use camino::Utf8Path;
+use clap::error::ErrorKind;
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
-use crate::{Config, command_builder::Executer, options::ToSwitch, switch};
+use crate::{
+    Config, CustomTarget, Errors, Host, Progress, TargetConfig,
+    bisect::{self, Bisect},
+    cache::{Fingerprint, SwitchCache},
+    check::{self, Check, Configurations, Format},
+    command_builder::{self, CommandError, Execute as _, Executer},
+    diff::ClosureDiff,
+    fleet::{self, Fleet, Stage},
+    generation, git,
+    health::{self, HealthChecks},
+    history::{self, Filter, History, Outcome, StageDuration, SwitchRecord, recorded_switch},
+    hooks::{HookContext, Hooks},
+    lock::{self, FlakeLock, Staleness},
+    nix::{self, NixInfo, Version},
+    nix_log::{self, LogState},
+    options::{self, Operation, Switch, Task, ToSwitch},
+    outdated, switch,
+    workflow::{self, Step},
+};
+
+/// A recent nix with flakes enabled in its config.
+fn flakes_nix() -> NixInfo {
+    NixInfo::new(
+        Some(Version(2, 28, 0)),
+        Box::new(["nix-command".into(), "flakes".into()]),
+    )
+}
+
+/// A temporary directory, removed when dropped so failing tests don't leave it behind.
+struct TempDir(PathBuf);
+
+impl TempDir {
+    /// Creates an empty directory with the given name, unique to this test process.
+    fn new(name: &str) -> Self {
+        let path =
+            std::env::temp_dir().join(format!("system-manager-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+}
+
+impl std::ops::Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Runs git in the repository as a test user, returning its trimmed stdout.
+fn run_git(repo: &Path, args: &[&str]) -> String {
+    let output = std::process::Command::new("git")
+        .arg("-C")
+        .arg(repo)
+        .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+        .args(args)
+        .output()
+        .expect("Git should run.");
+    assert!(output.status.success(), "git {args:?} failed.");
+    String::from_utf8(output.stdout).unwrap().trim().to_owned()
+}
+
+/// Creates a git repository in the directory, with a commit of 'flake.nix' with the given text.
+fn git_repo(repo: &Path, flake: &str) {
+    run_git(repo, &["init", "--quiet"]);
+    std::fs::write(repo.join("flake.nix"), flake).unwrap();
+    run_git(repo, &["add", "flake.nix"]);
+    run_git(repo, &["commit", "--quiet", "-m", "init"]);
+}
 
 #[test]
 fn system_switch() {
@@ -10,10 +90,15 @@ fn system_switch() {
         &Config {
             identity: "test_identity".into(),
             nix_path: Utf8Path::new("/path/to/flake.nix").into(),
+            ..Default::default()
+        },
+        &Switch {
+            targets: Box::new([ToSwitch::System { offline: false }]),
+            update: true,
+            ..Default::default()
         },
-        &[ToSwitch::System { offline: false }],
-        true,
-        Executer::new(true, &mut output),
+        &flakes_nix(),
+        &mut Executer::new(true, &mut output),
     )
     .expect("Unable to run test commands.");
 
@@ -30,11 +115,11 @@ fn system_switch() {
     );
     assert_eq!(
         outputs.next().unwrap(),
-        "nix flake update --flake /path/to/flake.nix"
+        "nix --extra-experimental-features pipe-operators flake update --flake /path/to/flake.nix"
     );
     assert_eq!(
         outputs.next().unwrap(),
-        "sudo nixos-rebuild --option experimental-features 'nix-command flakes pipe-operators' switch --flake /path/to/flake.nix#test_identity"
+        "sudo nixos-rebuild --option extra-experimental-features 'nix-command flakes pipe-operators' switch --flake /path/to/flake.nix#test_identity"
     );
 
     assert!(outputs.next().is_none());
@@ -48,10 +133,15 @@ fn system_switch_no_update() {
         &Config {
             identity: "test_identity".into(),
             nix_path: Utf8Path::new("/path/to/flake.nix").into(),
+            ..Default::default()
         },
-        &[ToSwitch::System { offline: false }],
-        false,
-        Executer::new(true, &mut output),
+        &Switch {
+            targets: Box::new([ToSwitch::System { offline: false }]),
+            update: false,
+            ..Default::default()
+        },
+        &flakes_nix(),
+        &mut Executer::new(true, &mut output),
     )
     .expect("Unable to run test commands.");
 
@@ -68,7 +158,7 @@ fn system_switch_no_update() {
     );
     assert_eq!(
         outputs.next().unwrap(),
-        "sudo nixos-rebuild --option experimental-features 'nix-command flakes pipe-operators' switch --flake /path/to/flake.nix#test_identity"
+        "sudo nixos-rebuild --option extra-experimental-features 'nix-command flakes pipe-operators' switch --flake /path/to/flake.nix#test_identity"
     );
 
     assert!(outputs.next().is_none());
@@ -82,10 +172,15 @@ fn home_switch() {
         &Config {
             identity: "test_identity".into(),
             nix_path: Utf8Path::new("/path/to/flake.nix").into(),
+            ..Default::default()
+        },
+        &Switch {
+            targets: Box::new([ToSwitch::Home]),
+            update: true,
+            ..Default::default()
         },
-        &[ToSwitch::Home],
-        true,
-        Executer::new(true, &mut output),
+        &flakes_nix(),
+        &mut Executer::new(true, &mut output),
     )
     .expect("Unable to run test commands.");
 
@@ -94,11 +189,11 @@ fn home_switch() {
 
     assert_eq!(
         outputs.next().unwrap(),
-        "nix flake update --flake /path/to/flake.nix"
+        "nix --extra-experimental-features pipe-operators flake update --flake /path/to/flake.nix"
     );
     assert_eq!(
         outputs.next().unwrap(),
-        "home-manager switch --flake /path/to/flake.nix#test_identity"
+        "home-manager --option extra-experimental-features pipe-operators switch --flake /path/to/flake.nix#test_identity"
     );
 
     assert!(outputs.next().is_none());
@@ -112,20 +207,2015 @@ fn home_switch_no_update() {
         &Config {
             identity: "test_identity".into(),
             nix_path: Utf8Path::new("/path/to/flake.nix").into(),
+            ..Default::default()
+        },
+        &Switch {
+            targets: Box::new([ToSwitch::Home]),
+            update: false,
+            ..Default::default()
+        },
+        &flakes_nix(),
+        &mut Executer::new(true, &mut output),
+    )
+    .expect("Unable to run test commands.");
+
+    let binding = String::from_utf8(output).expect("Output contained non-utf8 chars.");
+    let mut outputs = binding.split_terminator('\n');
+
+    assert_eq!(
+        outputs.next().unwrap(),
+        "home-manager --option extra-experimental-features pipe-operators switch --flake /path/to/flake.nix#test_identity"
+    );
+
+    assert!(outputs.next().is_none());
+}
+
+#[test]
+fn extra_args() {
+    let mut output = Vec::new();
+
+    switch(
+        &Config {
+            identity: "test_identity".into(),
+            nix_path: Utf8Path::new("/path/to/flake.nix").into(),
+            system: TargetConfig {
+                extra_args: Box::new(["--show-trace".into()]),
+                nix_options: [("max-jobs".into(), "4".into())].into(),
+            },
+            home: TargetConfig {
+                extra_args: Box::new(["-b".into(), "backup".into()]),
+                nix_options: [("substituters".into(), "https://a https://b".into())].into(),
+            },
+            ..Default::default()
+        },
+        &Switch {
+            targets: Box::new([ToSwitch::System { offline: false }, ToSwitch::Home]),
+            update: false,
+            extra_args: Box::new(["--impure".into()]),
+            ..Default::default()
+        },
+        &flakes_nix(),
+        &mut Executer::new(true, &mut output),
+    )
+    .expect("Unable to run test commands.");
+
+    let binding = String::from_utf8(output).expect("Output contained non-utf8 chars.");
+    let mut outputs = binding.split_terminator('\n').skip(2);
+
+    assert_eq!(
+        outputs.next().unwrap(),
+        "sudo nixos-rebuild --option extra-experimental-features 'nix-command flakes pipe-operators' switch --flake /path/to/flake.nix#test_identity --option max-jobs 4 --show-trace --impure"
+    );
+    assert_eq!(
+        outputs.next().unwrap(),
+        "home-manager --option extra-experimental-features pipe-operators switch --flake /path/to/flake.nix#test_identity --option substituters 'https://a https://b' -b backup --impure"
+    );
+
+    assert!(outputs.next().is_none());
+}
+
+#[test]
+fn shell_quoting() {
+    assert_eq!(
+        command_builder::quote("/path/to/flake.nix#web"),
+        "/path/to/flake.nix#web"
+    );
+    assert_eq!(command_builder::quote("#web"), "'#web'");
+    assert_eq!(command_builder::quote("it's"), r"'it'\''s'");
+
+    // Each argument reaches the command as a single word.
+    let args = ["a", "#x", "b c", "it's", ""].map(command_builder::quote);
+    let output = Executer::new(false, std::io::sink())
+        .capture(&format!("printf '[%s]' {}", args.join(" ")))
+        .expect("Unable to run test commands.");
+    assert_eq!(output, "[a][#x][b c][it's][]");
+}
+
+#[test]
+fn old_nix_features() {
+    let mut output = Vec::new();
+
+    switch(
+        &Config {
+            identity: "test_identity".into(),
+            nix_path: Utf8Path::new("/path/to/flake.nix").into(),
+            ..Default::default()
+        },
+        &Switch {
+            targets: Box::new([ToSwitch::System { offline: false }, ToSwitch::Home]),
+            update: true,
+            ..Default::default()
+        },
+        &NixInfo::new(Some(Version(2, 18, 1)), Box::new([])),
+        &mut Executer::new(true, &mut output),
+    )
+    .expect("Unable to run test commands.");
+
+    let binding = String::from_utf8(output).expect("Output contained non-utf8 chars.");
+    let mut outputs = binding.split_terminator('\n').skip(2);
+
+    assert_eq!(
+        outputs.next().unwrap(),
+        "nix --extra-experimental-features 'nix-command flakes' flake update --flake /path/to/flake.nix"
+    );
+    assert_eq!(
+        outputs.next().unwrap(),
+        "sudo nixos-rebuild --option extra-experimental-features 'nix-command flakes' switch --flake /path/to/flake.nix#test_identity"
+    );
+    assert_eq!(
+        outputs.next().unwrap(),
+        "home-manager --option extra-experimental-features 'nix-command flakes' switch --flake /path/to/flake.nix#test_identity"
+    );
+
+    assert!(outputs.next().is_none());
+}
+
+#[test]
+fn nix_probe_parsing() {
+    assert_eq!(
+        nix::parse_version("nix (Nix) 2.24.10\n"),
+        Some(Version(2, 24, 10))
+    );
+    assert_eq!(
+        nix::parse_version("nix (Lix, like Nix) 2.91.1"),
+        Some(Version(2, 91, 1))
+    );
+    assert_eq!(
+        nix::parse_version("nix (Nix) 2.26.0pre20250101_abcdef"),
+        Some(Version(2, 26, 0))
+    );
+    assert_eq!(nix::parse_version(""), None);
+
+    let features = nix::parse_features(
+        "eval-cache = true\nexperimental-features = flakes nix-command\nkeep-going = false\n",
+    );
+    assert_eq!(&*features, &["flakes".into(), "nix-command".into()]);
+}
+
+#[test]
+fn hooks() {
+    let mut output = Vec::new();
+
+    switch(
+        &Config {
+            identity: "test_identity".into(),
+            nix_path: Utf8Path::new("/path/to/flake.nix").into(),
+            hooks: Hooks {
+                before_update: Box::new(["nix fmt".into()]),
+                after_update: Box::new(["git add flake.lock".into()]),
+                before_target: Box::new(["echo $SYSTEM_MANAGER_TARGET".into()]),
+                after_target: Box::new(["notify-send done".into()]),
+                on_failure: Box::new(["notify-send failed".into()]),
+            },
+            ..Default::default()
+        },
+        &Switch {
+            targets: Box::new([ToSwitch::Home]),
+            update: true,
+            ..Default::default()
+        },
+        &flakes_nix(),
+        &mut Executer::new(true, &mut output),
+    )
+    .expect("Unable to run test commands.");
+
+    let binding = String::from_utf8(output).expect("Output contained non-utf8 chars.");
+    let mut outputs = binding.split_terminator('\n');
+    let env = "cd /path/to/flake.nix && SYSTEM_MANAGER_IDENTITY=test_identity SYSTEM_MANAGER_PATH=/path/to/flake.nix";
+
+    assert_eq!(outputs.next().unwrap(), format!("{env} sh -c 'nix fmt'"));
+    assert_eq!(
+        outputs.next().unwrap(),
+        "nix --extra-experimental-features pipe-operators flake update --flake /path/to/flake.nix"
+    );
+    assert_eq!(
+        outputs.next().unwrap(),
+        format!("{env} sh -c 'git add flake.lock'")
+    );
+    assert_eq!(
+        outputs.next().unwrap(),
+        format!("{env} SYSTEM_MANAGER_TARGET=home sh -c 'echo $SYSTEM_MANAGER_TARGET'")
+    );
+    assert_eq!(
+        outputs.next().unwrap(),
+        "home-manager --option extra-experimental-features pipe-operators switch --flake /path/to/flake.nix#test_identity"
+    );
+    assert_eq!(
+        outputs.next().unwrap(),
+        format!(
+            "{env} SYSTEM_MANAGER_TARGET=home SYSTEM_MANAGER_OUTCOME=success sh -c 'notify-send done'"
+        )
+    );
+
+    assert!(outputs.next().is_none());
+}
+
+#[test]
+fn failure_hooks() {
+    let flake = std::env::temp_dir().join(format!("failure-hooks-{}", std::process::id()));
+    std::fs::create_dir_all(&flake).unwrap();
+    let mut output = Vec::new();
+
+    let result = switch(
+        &Config {
+            identity: "test_identity".into(),
+            nix_path: Utf8Path::from_path(&flake).unwrap().into(),
+            hooks: Hooks {
+                before_target: Box::new(["pwd".into()]),
+                on_failure: Box::new([
+                    "echo \"$SYSTEM_MANAGER_TARGET $SYSTEM_MANAGER_OUTCOME\"".into()
+                ]),
+                ..Default::default()
+            },
+            targets: [(
+                "broken".into(),
+                CustomTarget {
+                    command: "false".into(),
+                    privileged: false,
+                },
+            )]
+            .into(),
+            ..Default::default()
+        },
+        &Switch {
+            targets: Box::new([ToSwitch::Custom {
+                name: "broken".into(),
+            }]),
+            ..Default::default()
+        },
+        &flakes_nix(),
+        &mut Executer::new(false, &mut output),
+    );
+    std::fs::remove_dir_all(&flake).unwrap();
+
+    assert!(matches!(
+        result,
+        Err(Errors::CommandError(CommandError::Failed { command })) if &*command == "false"
+    ));
+    // Hooks run in the flake directory, & failure hooks know which target failed.
+    assert_eq!(
+        String::from_utf8(output).expect("Output contained non-utf8 chars."),
+        format!("{}\nbroken failure\n", flake.display())
+    );
+}
+
+#[test]
+fn custom_targets() {
+    let mut output = Vec::new();
+
+    switch(
+        &Config {
+            identity: "test_identity".into(),
+            nix_path: Utf8Path::new("/path/to/flake.nix").into(),
+            targets: [
+                (
+                    "droid".into(),
+                    CustomTarget {
+                        command: "nix-on-droid switch --flake {path}#{identity}".into(),
+                        privileged: false,
+                    },
+                ),
+                (
+                    "container".into(),
+                    CustomTarget {
+                        command: "nixos-container update {identity}".into(),
+                        privileged: true,
+                    },
+                ),
+            ]
+            .into(),
+            target_order: Box::new(["container".into(), "home".into(), "droid".into()]),
+            ..Default::default()
+        },
+        &Switch {
+            all: true,
+            extra_args: Box::new(["--show-trace".into()]),
+            ..Default::default()
+        },
+        &flakes_nix(),
+        &mut Executer::new(true, &mut output),
+    )
+    .expect("Unable to run test commands.");
+
+    let binding = String::from_utf8(output).expect("Output contained non-utf8 chars.");
+    let mut outputs = binding.split_terminator('\n');
+
+    assert_eq!(
+        outputs.next().unwrap(),
+        "echo 'Sudo perms required for system rebuild.'"
+    );
+    assert_eq!(
+        outputs.next().unwrap(),
+        "sudo echo 'Sudo perms given for system rebuild.'"
+    );
+    assert_eq!(
+        outputs.next().unwrap(),
+        "sudo nixos-container update test_identity --show-trace"
+    );
+    assert_eq!(
+        outputs.next().unwrap(),
+        "home-manager --option extra-experimental-features pipe-operators switch --flake /path/to/flake.nix#test_identity --show-trace"
+    );
+    assert_eq!(
+        outputs.next().unwrap(),
+        "nix-on-droid switch --flake /path/to/flake.nix#test_identity --show-trace"
+    );
+
+    assert!(outputs.next().is_none());
+}
+
+#[test]
+fn unknown_custom_target() {
+    let mut output = Vec::new();
+
+    let result = switch(
+        &Config {
+            identity: "test_identity".into(),
+            nix_path: Utf8Path::new("/path/to/flake.nix").into(),
+            ..Default::default()
+        },
+        &Switch {
+            targets: Box::new([ToSwitch::Custom {
+                name: "missing".into(),
+            }]),
+            ..Default::default()
+        },
+        &flakes_nix(),
+        &mut Executer::new(true, &mut output),
+    );
+
+    assert!(matches!(result, Err(Errors::UnknownTarget { name }) if &*name == "missing"));
+    assert!(output.is_empty());
+}
+
+#[test]
+fn workflow_steps() {
+    let mut output = Vec::new();
+
+    let report = workflow::run(
+        &Config {
+            identity: "test_identity".into(),
+            nix_path: Utf8Path::new("/path/to/flake.nix").into(),
+            workflows: [(
+                "daily".into(),
+                Box::new([
+                    Step::Update,
+                    Step::Switch {
+                        targets: Box::new(["home".into()]),
+                    },
+                    Step::Gc {
+                        delete_older_than: Some("14d".into()),
+                        privileged: true,
+                    },
+                    Step::Commit {
+                        message: "Update lock".into(),
+                        paths: Box::new(["flake.lock".into()]),
+                    },
+                ]) as Box<[Step]>,
+            )]
+            .into(),
+            ..Default::default()
         },
-        &[ToSwitch::Home],
-        false,
-        Executer::new(true, &mut output),
+        "daily",
+        &flakes_nix(),
+        &mut Executer::new(true, &mut output),
+        None,
     )
     .expect("Unable to run test commands.");
 
+    assert!(
+        report
+            .to_string()
+            .starts_with("Workflow 'daily' summary:\n  done    update")
+    );
+    report.into_result().expect("Workflow should succeed.");
+
     let binding = String::from_utf8(output).expect("Output contained non-utf8 chars.");
     let mut outputs = binding.split_terminator('\n');
 
     assert_eq!(
         outputs.next().unwrap(),
-        "home-manager switch --flake /path/to/flake.nix#test_identity"
+        "nix --extra-experimental-features pipe-operators flake update --flake /path/to/flake.nix"
+    );
+    assert_eq!(
+        outputs.next().unwrap(),
+        "home-manager --option extra-experimental-features pipe-operators switch --flake /path/to/flake.nix#test_identity"
+    );
+    assert_eq!(
+        outputs.next().unwrap(),
+        "sudo nix-collect-garbage --delete-older-than 14d"
+    );
+    assert_eq!(
+        outputs.next().unwrap(),
+        "git -C /path/to/flake.nix diff --quiet HEAD -- flake.lock || git -C /path/to/flake.nix commit -m 'Update lock' -- flake.lock"
     );
 
     assert!(outputs.next().is_none());
 }
+
+#[test]
+fn workflow_targets() {
+    let config = |targets: &[&str]| Config {
+        identity: "test_identity".into(),
+        nix_path: Utf8Path::new("/path/to/my flake").into(),
+        workflows: [(
+            "deploy".into(),
+            Box::new([
+                Step::Switch {
+                    targets: targets.iter().map(|&target| target.into()).collect(),
+                },
+                Step::Commit {
+                    message: "Deploy".into(),
+                    paths: Box::new(["flake.lock".into()]),
+                },
+            ]) as Box<[Step]>,
+        )]
+        .into(),
+        ..Default::default()
+    };
+
+    let mut output = Vec::new();
+    workflow::run(
+        &config(&["both"]),
+        "deploy",
+        &flakes_nix(),
+        &mut Executer::new(true, &mut output),
+        None,
+    )
+    .expect("Unable to run test commands.")
+    .into_result()
+    .expect("Workflow should succeed.");
+
+    let binding = String::from_utf8(output).expect("Output contained non-utf8 chars.");
+    let outputs: Vec<_> = binding
+        .lines()
+        .filter_map(|line| {
+            line.split_whitespace()
+                .find(|word| !word.starts_with("sudo"))
+        })
+        .collect();
+    assert_eq!(
+        outputs,
+        ["echo", "echo", "nixos-rebuild", "home-manager", "git"]
+    );
+    assert!(binding.ends_with(
+        "git -C '/path/to/my flake' diff --quiet HEAD -- flake.lock || git -C '/path/to/my flake' commit -m Deploy -- flake.lock\n"
+    ));
+
+    // The other targets given with "all" aren't silently dropped.
+    let report = workflow::run(
+        &config(&["all", "home"]),
+        "deploy",
+        &flakes_nix(),
+        &mut Executer::new(true, Vec::new()),
+        None,
+    )
+    .expect("Unable to run test commands.");
+    assert!(matches!(report.into_result(), Err(Errors::MixedAllTarget)));
+}
+
+#[test]
+fn workflow_stops_at_failure() {
+    let mut output = Vec::new();
+
+    let report = workflow::run(
+        &Config {
+            workflows: [(
+                "failing".into(),
+                Box::new([
+                    Step::Hook {
+                        command: "echo first".into(),
+                    },
+                    Step::Hook {
+                        command: "exit 1".into(),
+                    },
+                    Step::Hook {
+                        command: "echo third".into(),
+                    },
+                ]) as Box<[Step]>,
+            )]
+            .into(),
+            ..Default::default()
+        },
+        "failing",
+        &flakes_nix(),
+        &mut Executer::new(false, &mut output),
+        None,
+    )
+    .expect("Workflow should exist.");
+
+    let summary = report.to_string();
+    assert!(summary.contains("done    hook 'echo first'"));
+    assert!(summary.contains("failed  hook 'exit 1'"));
+    assert!(summary.contains("skipped hook 'echo third'"));
+    assert!(report.into_result().is_err());
+
+    assert_eq!(String::from_utf8(output).unwrap(), "first\n");
+}
+
+#[test]
+fn switch_diff() {
+    let mut output = Vec::new();
+
+    switch(
+        &Config {
+            identity: "test_identity".into(),
+            nix_path: Utf8Path::new("/path/to/flake.nix").into(),
+            ..Default::default()
+        },
+        &Switch {
+            targets: Box::new([ToSwitch::Home]),
+            diff: true,
+            ..Default::default()
+        },
+        &flakes_nix(),
+        &mut Executer::new(true, &mut output),
+    )
+    .expect("Unable to run test commands.");
+
+    let binding = String::from_utf8(output).expect("Output contained non-utf8 chars.");
+    let mut outputs = binding.split_terminator('\n');
+
+    assert_eq!(
+        outputs.next().unwrap(),
+        "nix --extra-experimental-features pipe-operators build --no-link --print-out-paths /path/to/flake.nix#homeConfigurations.test_identity.activationPackage"
+    );
+    assert_eq!(
+        outputs.next().unwrap(),
+        "nix --extra-experimental-features pipe-operators store diff-closures ${XDG_STATE_HOME:-$HOME/.local/state}/nix/profiles/home-manager $(nix --extra-experimental-features pipe-operators build --no-link --print-out-paths /path/to/flake.nix#homeConfigurations.test_identity.activationPackage)"
+    );
+    assert_eq!(
+        outputs.next().unwrap(),
+        "home-manager --option extra-experimental-features pipe-operators switch --flake /path/to/flake.nix#test_identity"
+    );
+
+    assert!(outputs.next().is_none());
+}
+
+#[test]
+fn closure_diff_parsing() {
+    let diff = ClosureDiff::parse(
+        "firefox: 120.0 → 121.0, +1024.0 KiB\n\
+         hello: ∅ → 2.12, +50.0 KiB\n\
+         nano: 7.2 → ∅, -74.0 KiB\n\
+         glibc: -0.5 KiB\n",
+    );
+
+    assert_eq!(
+        diff.changed,
+        vec![("firefox".into(), "120.0".into(), "121.0".into())]
+    );
+    assert_eq!(diff.added, vec![("hello".into(), "2.12".into())]);
+    assert_eq!(diff.removed, vec![("nano".into(), "7.2".into())]);
+    assert_eq!(diff.size_delta, 999.5);
+    assert!(diff.to_string().ends_with("Size: +999.5 KiB"));
+
+    let coloured = ClosureDiff::parse(
+        "firefox: 120.0 → 121.0, \x1b[31;1m+1024.0 KiB\x1b[0m\n\
+         glibc: \x1b[32;1m-0.5 KiB\x1b[0m\n",
+    );
+    assert_eq!(
+        coloured.changed,
+        vec![("firefox".into(), "120.0".into(), "121.0".into())]
+    );
+    assert_eq!(coloured.size_delta, 1023.5);
+}
+
+#[test]
+fn confirm_plan() {
+    let mut output = Vec::new();
+
+    switch(
+        &Config {
+            identity: "test_identity".into(),
+            nix_path: Utf8Path::new("/path/to/flake.nix").into(),
+            confirm_system_switch: true,
+            ..Default::default()
+        },
+        &Switch {
+            targets: Box::new([ToSwitch::System { offline: false }, ToSwitch::Home]),
+            update: true,
+            ..Default::default()
+        },
+        &flakes_nix(),
+        &mut Executer::new(true, &mut output),
+    )
+    .expect("Unable to run test commands.");
+
+    let binding = String::from_utf8(output).expect("Output contained non-utf8 chars.");
+    let mut outputs = binding.split_terminator('\n');
+
+    // The plan is confirmed before sudo is requested & the inputs are updated.
+    assert_eq!(outputs.next().unwrap(), "Switch plan:");
+    assert_eq!(outputs.next().unwrap(), "  Identity: test_identity");
+    assert_eq!(outputs.next().unwrap(), "  Flake: /path/to/flake.nix");
+    assert_eq!(outputs.next().unwrap(), "  Update inputs: yes");
+    assert_eq!(outputs.next().unwrap(), "  Targets: system, home");
+    assert!(outputs.next().unwrap().starts_with("echo 'Sudo perms"));
+    assert!(outputs.next().unwrap().starts_with("sudo echo"));
+    assert!(outputs.next().unwrap().contains("flake update"));
+    assert!(outputs.next().unwrap().starts_with("sudo nixos-rebuild"));
+    assert!(outputs.next().unwrap().starts_with("home-manager"));
+    assert!(outputs.next().is_none());
+
+    // Skipping confirmation omits the plan.
+    let mut output = Vec::new();
+    switch(
+        &Config {
+            identity: "test_identity".into(),
+            nix_path: Utf8Path::new("/path/to/flake.nix").into(),
+            confirm_system_switch: true,
+            ..Default::default()
+        },
+        &Switch {
+            targets: Box::new([ToSwitch::System { offline: false }]),
+            yes: true,
+            ..Default::default()
+        },
+        &flakes_nix(),
+        &mut Executer::new(true, &mut output),
+    )
+    .expect("Unable to run test commands.");
+
+    assert!(!String::from_utf8(output).unwrap().contains("Switch plan:"));
+}
+
+/// A 'flake.lock' with a direct input, a followed input & an input without a revision.
+const FLAKE_LOCK: &str = r#"{
+  "nodes": {
+    "home-manager": {
+      "inputs": { "nixpkgs": ["nixpkgs"] },
+      "locked": { "lastModified": 1700000000, "rev": "aaaa", "type": "github" }
+    },
+    "nixpkgs": {
+      "locked": { "lastModified": 1710000000, "rev": "bbbb", "type": "github" }
+    },
+    "local": {
+      "locked": { "path": "/some/path", "type": "path" }
+    },
+    "root": {
+      "inputs": { "home-manager": "home-manager", "nixpkgs": "nixpkgs", "local": "local", "pkgs": ["nixpkgs"] }
+    }
+  },
+  "root": "root",
+  "version": 7
+}"#;
+
+#[test]
+fn flake_lock_inputs() {
+    let lock = FlakeLock::parse(FLAKE_LOCK).expect("Lock should parse.");
+    let inputs = lock.inputs();
+
+    let names: Vec<_> = inputs.iter().map(|input| &*input.name).collect();
+    assert_eq!(names, ["home-manager", "local", "nixpkgs"]);
+
+    assert_eq!(inputs[0].rev.as_deref(), Some("aaaa"));
+    assert_eq!(
+        inputs[0].last_modified.map(|time| time.timestamp()),
+        Some(1700000000)
+    );
+    assert_eq!(inputs[1].rev, None);
+    assert_eq!(inputs[1].last_modified, None);
+}
+
+#[test]
+fn lock_bisect_changes() {
+    let old: serde_json::Value = serde_json::from_str(FLAKE_LOCK).unwrap();
+    let new = FLAKE_LOCK
+        .replace("\"aaaa\"", "\"cccc\"")
+        .replace("\"bbbb\"", "\"dddd\"");
+    let new: serde_json::Value = serde_json::from_str(&new).unwrap();
+
+    let changes = lock::changes(&old, &new);
+    let names: Vec<_> = changes.iter().map(|change| &*change.name).collect();
+    assert_eq!(names, ["home-manager", "nixpkgs"]);
+    assert_eq!(changes[1].old_rev.as_deref(), Some("bbbb"));
+    assert_eq!(changes[1].new_rev.as_deref(), Some("dddd"));
+    assert!(lock::changes(&old, &old).is_empty());
+
+    let mixed = lock::apply(&old, &new, &[&changes[1]]);
+    let inputs = &mixed["nodes"]["root"]["inputs"];
+    assert_eq!(inputs["nixpkgs"], "bisect-nixpkgs");
+    assert_eq!(inputs["home-manager"], "home-manager");
+    assert_eq!(mixed["nodes"]["bisect-nixpkgs"]["locked"]["rev"], "dddd");
+    assert_eq!(mixed["nodes"]["home-manager"]["locked"]["rev"], "aaaa");
+
+    let remaining = lock::changes(&mixed, &new);
+    let names: Vec<_> = remaining.iter().map(|change| &*change.name).collect();
+    assert_eq!(names, ["home-manager"]);
+}
+
+#[test]
+fn bisect_search() {
+    let changes: Vec<_> = (0..8).collect();
+    let mut executer = Executer::new(false, std::io::sink());
+    let mut steps = Vec::new();
+
+    // The sixth change breaks the build, as scripted by the test command.
+    let culprit = bisect::search(&changes, |count, remaining| {
+        steps.push((count, remaining));
+        Ok(executer.execute(&format!("test {count} -lt 6")).is_ok())
+    })
+    .unwrap();
+    assert_eq!(*culprit, 5);
+    assert_eq!(steps, [(4, 7), (6, 3), (5, 1)]);
+
+    assert!(matches!(
+        bisect::search(&changes, |_, _| Err(Errors::Cancelled)),
+        Err(Errors::Cancelled)
+    ));
+    assert!(matches!(
+        bisect::search::<u8>(&[], |_, _| Ok(true)),
+        Err(Errors::NoLockChanges)
+    ));
+
+    // Every displayed build is good, so the last update is blamed & the temporary lock removed.
+    let directory = std::env::temp_dir().join(format!("bisect-{}", std::process::id()));
+    std::fs::create_dir_all(&directory).unwrap();
+    let new = FLAKE_LOCK
+        .replace("\"aaaa\"", "\"cccc\"")
+        .replace("\"bbbb\"", "\"dddd\"");
+    std::fs::write(directory.join("good.lock"), FLAKE_LOCK).unwrap();
+    std::fs::write(directory.join("bad.lock"), new).unwrap();
+
+    let mut output = Vec::new();
+    let change = bisect::bisect(
+        &Config {
+            identity: "test_identity".into(),
+            nix_path: Utf8Path::new("/path/to/flake.nix").into(),
+            ..Default::default()
+        },
+        &Bisect {
+            good: directory.join("good.lock").to_str().unwrap().into(),
+            bad: Some(directory.join("bad.lock").to_str().unwrap().into()),
+            target: ToSwitch::Home,
+            command: Some("true".into()),
+        },
+        &flakes_nix(),
+        &mut Executer::new(true, &mut output),
+    )
+    .unwrap();
+    std::fs::remove_dir_all(&directory).unwrap();
+
+    assert_eq!(&*change.name, "nixpkgs");
+    assert!(
+        !std::env::temp_dir()
+            .join(format!("system-manager-bisect-{}.lock", std::process::id()))
+            .exists()
+    );
+}
+
+#[test]
+fn outdated_inputs() {
+    let pinned = FlakeLock::parse(FLAKE_LOCK).expect("Lock should parse.");
+    let latest = FLAKE_LOCK
+        .replace("\"bbbb\"", "\"dddd\"")
+        .replace("1710000000", "1720000000");
+    let latest = FlakeLock::parse(&latest).expect("Lock should parse.");
+
+    let inputs = outdated::compare(&pinned, &latest);
+    let outdated: Vec<_> = inputs
+        .iter()
+        .filter(|input| input.is_outdated())
+        .map(|input| &*input.name)
+        .collect();
+    assert_eq!(outdated, ["nixpkgs"]);
+    assert_eq!(inputs[2].latest_rev.as_deref(), Some("dddd"));
+
+    let report = outdated::Report(&inputs).to_string();
+    let lines: Vec<_> = report.lines().collect();
+    assert!(lines[0].starts_with("home-manager: up to date (aaaa, "));
+    assert_eq!(lines[1], "local: up to date (unknown, unknown age)");
+    assert!(lines[2].starts_with("nixpkgs: bbbb -> dddd (pinned "));
+}
+
+#[test]
+fn outdated_local_input() {
+    let nix = NixInfo::probe();
+    // Resolving inputs needs nix, though not the network as the input is a local repository.
+    if nix.version().is_none() {
+        eprintln!("Skipping outdated_local_input, as nix isn't installed.");
+        return;
+    }
+
+    let root = TempDir::new("outdated");
+    let (input, flake) = (root.join("input"), root.join("flake"));
+    std::fs::create_dir_all(&input).unwrap();
+    std::fs::create_dir_all(&flake).unwrap();
+    git_repo(&input, "{ outputs = _: { }; }");
+    let pinned = run_git(&input, &["rev-parse", "HEAD"]);
+
+    std::fs::write(
+        flake.join("flake.nix"),
+        format!(
+            "{{ inputs.local.url = \"git+file://{}\"; outputs = _: {{ }}; }}",
+            input.display()
+        ),
+    )
+    .unwrap();
+    let config = Config {
+        identity: "test_identity".into(),
+        nix_path: Utf8Path::from_path(&flake).unwrap().into(),
+        ..Default::default()
+    };
+    let mut executer = Executer::new(false, std::io::stderr());
+    executer
+        .execute(&format!(
+            "nix{} flake lock {}",
+            nix.nix_args(),
+            flake.display()
+        ))
+        .expect("The flake should lock.");
+    let lock = std::fs::read_to_string(flake.join("flake.lock")).unwrap();
+
+    run_git(
+        &input,
+        &["commit", "--quiet", "--allow-empty", "-m", "update"],
+    );
+    let latest = run_git(&input, &["rev-parse", "HEAD"]);
+    let inputs = outdated::check(&config, &nix, &mut executer).expect("Inputs should resolve.");
+
+    assert_eq!(inputs.len(), 1);
+    assert!(inputs[0].is_outdated());
+    assert_eq!(inputs[0].pinned_rev.as_deref(), Some(&*pinned));
+    assert_eq!(inputs[0].latest_rev.as_deref(), Some(&*latest));
+    // The real lock file is left untouched.
+    assert_eq!(
+        std::fs::read_to_string(flake.join("flake.lock")).unwrap(),
+        lock
+    );
+}
+
+#[test]
+fn generation_numbers() {
+    assert_eq!(generation::parse_number("system-42-link"), Some(42));
+    assert_eq!(generation::parse_number("home-manager-7-link"), Some(7));
+    assert_eq!(generation::parse_number("system"), None);
+}
+
+#[test]
+fn switch_history() {
+    let path = std::env::temp_dir().join(format!("system-manager-history-{}", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let history = History::new(&path);
+
+    let config = Config {
+        identity: "test_identity".into(),
+        nix_path: Utf8Path::new("/path/to/flake.nix").into(),
+        targets: [
+            (
+                "passing".into(),
+                CustomTarget {
+                    command: "true".into(),
+                    privileged: false,
+                },
+            ),
+            (
+                "failing".into(),
+                CustomTarget {
+                    command: "false".into(),
+                    privileged: false,
+                },
+            ),
+        ]
+        .into(),
+        ..Default::default()
+    };
+
+    for target in ["passing", "failing", "passing"] {
+        let _ = recorded_switch(
+            &config,
+            &Switch {
+                targets: Box::new([ToSwitch::Custom {
+                    name: target.into(),
+                }]),
+                ..Default::default()
+            },
+            &flakes_nix(),
+            &mut Executer::new(false, Vec::new()),
+            &history,
+        );
+    }
+
+    let records = history.records();
+    assert_eq!(records.len(), 3);
+    assert_eq!(&*records[1].targets, &["failing".into()]);
+    assert_eq!(records[1].outcome, Outcome::Failure);
+    assert_eq!(&*records[0].durations[0].stage, "passing");
+    assert!(records[1].durations.is_empty());
+
+    let failures = history.filter(&Filter {
+        outcome: Some(Outcome::Failure),
+        ..Default::default()
+    });
+    assert_eq!(failures.len(), 1);
+
+    let limited = history.filter(&Filter {
+        target: Some("passing".into()),
+        limit: Some(1),
+        ..Default::default()
+    });
+    assert_eq!(limited.len(), 1);
+    assert_eq!(limited[0].time, records[2].time);
+
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn generation_commit_lookup() {
+    let path =
+        std::env::temp_dir().join(format!("system-manager-generations-{}", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let history = History::new(&path);
+
+    let record = |commit: &str, system: u32, outcome| SwitchRecord {
+        time: chrono::Local::now(),
+        targets: Box::new(["system".into()]),
+        identity: "test_identity".into(),
+        flake_path: "/path/to/flake.nix".into(),
+        commit: Some(commit.into()),
+        dirty: Some(false),
+        lock_hash: None,
+        tree: None,
+        update: false,
+        offline: false,
+        system_generation: Some(system),
+        home_generation: None,
+        durations: Box::new([]),
+        outcome,
+    };
+
+    history
+        .append(&record("aaaa", 41, Outcome::Success))
+        .unwrap();
+    history
+        .append(&record("bbbb", 42, Outcome::Success))
+        .unwrap();
+    // A failed switch leaves the previous generation active.
+    history
+        .append(&record("cccc", 42, Outcome::Failure))
+        .unwrap();
+
+    let found = history
+        .generation(false, 42)
+        .expect("Generation was recorded.");
+    assert_eq!(found.commit.as_deref(), Some("bbbb"));
+    assert!(history.generation(true, 42).is_none());
+    assert!(history.generation(false, 43).is_none());
+
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn worktree_fingerprint() {
+    let repo = TempDir::new("worktree");
+    git_repo(&repo, "{ }");
+
+    let committed = git::worktree_hash(&repo).expect("Repository has a tree.");
+
+    // Untracked files aren't seen by nix, so they don't change the tree.
+    std::fs::write(repo.join("untracked.nix"), "{ }").unwrap();
+    assert_eq!(git::worktree_hash(&repo).as_ref(), Some(&committed));
+
+    std::fs::write(repo.join("flake.nix"), "{ outputs = _: { }; }").unwrap();
+    let modified = git::worktree_hash(&repo).expect("Repository has a tree.");
+    assert_ne!(modified, committed);
+    // The repository's own index is left untouched.
+    assert_eq!(git::is_dirty(&repo), Some(true));
+    run_git(&repo, &["diff", "--quiet", "--cached"]);
+}
+
+#[test]
+fn skip_up_to_date_targets() {
+    let repo = TempDir::new("cache");
+    git_repo(&repo, "{ }");
+
+    let config = Config {
+        identity: "test_identity".into(),
+        nix_path: Utf8Path::from_path(&repo).unwrap().into(),
+        ..Default::default()
+    };
+    let options = Switch::default();
+    let nix = flakes_nix();
+    let cache_path = repo.join("cache.json");
+    let active = |_: &ToSwitch| Some("/nix/store/abc-home-manager-generation".into());
+
+    let command = crate::switch_command(&config, &options, &ToSwitch::Home, &nix);
+    let fingerprint = Fingerprint::new(&config, &command).expect("The flake is in git.");
+    SwitchCache::open_with(&cache_path, active)
+        .record(&config, &ToSwitch::Home, fingerprint)
+        .unwrap();
+
+    let skip = |options: &Switch| {
+        let mut targets = vec![ToSwitch::System { offline: false }, ToSwitch::Home];
+        let mut output = Vec::new();
+        let mut progress = Progress::default();
+        crate::skip_up_to_date(
+            &config,
+            options,
+            &mut targets,
+            &SwitchCache::open_with(&cache_path, active),
+            &nix,
+            &mut Executer::new(true, &mut output),
+            &mut progress,
+        )
+        .unwrap();
+        let names: Vec<_> = targets
+            .iter()
+            .map(|target| target.name().to_owned())
+            .collect();
+        (
+            names,
+            String::from_utf8(output).unwrap(),
+            progress.durations,
+        )
+    };
+
+    // Only the home target was recorded, & is still active.
+    let (targets, output, durations) = skip(&options);
+    assert_eq!(targets, ["system"]);
+    assert_eq!(output, "home: up to date\n");
+    assert_eq!(durations.len(), 1);
+    assert_eq!(&*durations[0].0, "home");
+
+    let forced = Switch {
+        force: true,
+        ..Default::default()
+    };
+    assert_eq!(skip(&forced).0, ["system", "home"]);
+
+    std::fs::write(repo.join("flake.nix"), "{ outputs = _: { }; }").unwrap();
+    assert_eq!(skip(&options).0, ["system", "home"]);
+}
+
+#[test]
+fn stale_lock() {
+    let flake = std::env::temp_dir().join(format!("system-manager-stale-{}", std::process::id()));
+    std::fs::create_dir_all(&flake).unwrap();
+    std::fs::write(flake.join("flake.lock"), FLAKE_LOCK).unwrap();
+
+    let mut config = Config {
+        identity: "test_identity".into(),
+        nix_path: Utf8Path::from_path(&flake).unwrap().into(),
+        stale_lock: Staleness {
+            max_age_days: Some(30),
+            inputs: Box::new(["nixpkgs".into()]),
+            error: false,
+        },
+        ..Default::default()
+    };
+    let options = Switch {
+        targets: Box::new([ToSwitch::Home]),
+        ..Default::default()
+    };
+
+    let mut output = Vec::new();
+    switch(
+        &config,
+        &options,
+        &flakes_nix(),
+        &mut Executer::new(true, &mut output),
+    )
+    .expect("Stale inputs only warn by default.");
+    let output = String::from_utf8(output).unwrap();
+    assert!(output.starts_with("Warning: Inputs in 'flake.lock' are stale: nixpkgs ("));
+    assert!(!output.contains("home-manager ("));
+    assert!(output.contains("home-manager --option"));
+
+    config.stale_lock.error = true;
+    let mut output = Vec::new();
+    let result = switch(
+        &config,
+        &options,
+        &flakes_nix(),
+        &mut Executer::new(true, &mut output),
+    );
+    assert!(matches!(result, Err(Errors::StaleInputs { .. })));
+    assert!(
+        !String::from_utf8(output)
+            .unwrap()
+            .contains("home-manager --option")
+    );
+
+    // Inputs are never stale without a threshold.
+    let lock = FlakeLock::parse(FLAKE_LOCK).unwrap();
+    assert!(
+        Staleness::default()
+            .stale(&lock.inputs(), chrono::Local::now())
+            .is_empty()
+    );
+
+    let _ = std::fs::remove_dir_all(&flake);
+}
+
+#[test]
+fn health_checks() {
+    let mut output = Vec::new();
+
+    switch(
+        &Config {
+            identity: "test_identity".into(),
+            nix_path: Utf8Path::new("/path/to/flake.nix").into(),
+            health_checks: HealthChecks {
+                enabled: true,
+                required_units: Box::new(["sshd.service".into()]),
+                commands: Box::new(["curl -f localhost".into()]),
+                confirm_timeout: Some(30),
+            },
+            ..Default::default()
+        },
+        &Switch {
+            targets: Box::new([ToSwitch::System { offline: false }, ToSwitch::Home]),
+            ..Default::default()
+        },
+        &flakes_nix(),
+        &mut Executer::new(true, &mut output),
+    )
+    .expect("Unable to run test commands.");
+
+    let binding = String::from_utf8(output).expect("Output contained non-utf8 chars.");
+    let outputs: Vec<_> = binding.lines().skip(2).collect();
+
+    assert_eq!(
+        outputs,
+        [
+            "sudo nixos-rebuild --option extra-experimental-features 'nix-command flakes pipe-operators' switch --flake /path/to/flake.nix#test_identity",
+            "failed=\"$(systemctl --failed --no-legend --plain)\" && test -z \"$failed\"",
+            "systemctl is-active --quiet sshd.service",
+            "cd /path/to/flake.nix && SYSTEM_MANAGER_IDENTITY=test_identity SYSTEM_MANAGER_PATH=/path/to/flake.nix SYSTEM_MANAGER_TARGET=system sh -c 'curl -f localhost'",
+            // Home-manager switches aren't checked.
+            "home-manager --option extra-experimental-features pipe-operators switch --flake /path/to/flake.nix#test_identity",
+        ]
+    );
+
+    // Without systemctl, the failed units can't be checked.
+    let mut executer = Executer::new(false, std::io::sink());
+    assert!(
+        executer
+            .execute(&format!("PATH=/nonexistent; {}", health::NO_FAILED_UNITS))
+            .is_err()
+    );
+    let context = HookContext {
+        identity: "test_identity",
+        path: Utf8Path::new("/"),
+        target: Some("system"),
+        outcome: None,
+    };
+    let failing = HealthChecks {
+        enabled: true,
+        commands: Box::new(["false".into()]),
+        ..Default::default()
+    };
+    assert!(health::check(&failing, &context, &mut executer).is_err());
+
+    // A failed check rolls the system back.
+    let mut output = Vec::new();
+    let rolled_back = health::roll_back(
+        "false".into(),
+        &flakes_nix(),
+        &mut Executer::new(true, &mut output),
+    );
+    assert_eq!(
+        String::from_utf8(output).expect("Output contained non-utf8 chars."),
+        "Health check failed: false. Rolling back to the previous generation.\n\
+         sudo nixos-rebuild --option extra-experimental-features 'nix-command flakes pipe-operators' switch --rollback\n"
+    );
+    assert!(matches!(&rolled_back, Err(Errors::RolledBack { check }) if &**check == "false"));
+    assert_eq!(Outcome::from(&rolled_back), Outcome::RolledBack);
+}
+
+#[test]
+fn build_first() {
+    let mut output = Vec::new();
+
+    switch(
+        &Config {
+            identity: "test_identity".into(),
+            nix_path: Utf8Path::new("/path/to/flake.nix").into(),
+            ..Default::default()
+        },
+        &Switch {
+            targets: Box::new([ToSwitch::System { offline: false }, ToSwitch::Home]),
+            build_first: true,
+            ..Default::default()
+        },
+        &flakes_nix(),
+        &mut Executer::new(true, &mut output),
+    )
+    .expect("Unable to run test commands.");
+
+    let binding = String::from_utf8(output).expect("Output contained non-utf8 chars.");
+    let outputs: Vec<_> = binding.lines().skip(2).collect();
+
+    assert_eq!(
+        outputs,
+        [
+            "nix --extra-experimental-features pipe-operators build --no-link --print-out-paths /path/to/flake.nix#nixosConfigurations.test_identity.config.system.build.toplevel",
+            "nix --extra-experimental-features pipe-operators build --no-link --print-out-paths /path/to/flake.nix#homeConfigurations.test_identity.activationPackage",
+            "sudo nixos-rebuild --option extra-experimental-features 'nix-command flakes pipe-operators' switch --flake /path/to/flake.nix#test_identity",
+            "home-manager --option extra-experimental-features pipe-operators switch --flake /path/to/flake.nix#test_identity",
+        ]
+    );
+
+    // The system is built with the same label it's activated with.
+    let flake = TempDir::new("build-first-label");
+    git_repo(&flake, "{ }");
+    let commit = run_git(&flake, &["rev-parse", "--short=7", "HEAD"]);
+    let mut output = Vec::new();
+    switch(
+        &Config {
+            identity: "test_identity".into(),
+            nix_path: Utf8Path::from_path(&flake).unwrap().into(),
+            label_generations: true,
+            build_first: true,
+            ..Default::default()
+        },
+        &Switch {
+            targets: Box::new([ToSwitch::System { offline: false }]),
+            ..Default::default()
+        },
+        &flakes_nix(),
+        &mut Executer::new(true, &mut output),
+    )
+    .expect("Unable to run test commands.");
+
+    let binding = String::from_utf8(output).expect("Output contained non-utf8 chars.");
+    let path = flake.display();
+    assert_eq!(
+        binding.lines().skip(2).collect::<Vec<_>>(),
+        [
+            format!(
+                "env NIXOS_LABEL_VERSION=git-{commit} nix --extra-experimental-features pipe-operators build --no-link --print-out-paths {path}#nixosConfigurations.test_identity.config.system.build.toplevel --impure"
+            ),
+            format!(
+                "sudo env NIXOS_LABEL_VERSION=git-{commit} nixos-rebuild --option extra-experimental-features 'nix-command flakes pipe-operators' switch --flake {path}#test_identity --impure"
+            ),
+        ]
+    );
+
+    // Once built, a failed activation doesn't stop the other targets.
+    let mut output = Vec::new();
+    let result = switch(
+        &Config {
+            identity: "test_identity".into(),
+            nix_path: Utf8Path::new("/path/to/flake.nix").into(),
+            targets: [
+                (
+                    "failing".into(),
+                    CustomTarget {
+                        command: "false".into(),
+                        privileged: false,
+                    },
+                ),
+                (
+                    "passing".into(),
+                    CustomTarget {
+                        command: "echo passed".into(),
+                        privileged: false,
+                    },
+                ),
+            ]
+            .into(),
+            build_first: true,
+            ..Default::default()
+        },
+        &Switch {
+            targets: Box::new([
+                ToSwitch::Custom {
+                    name: "failing".into(),
+                },
+                ToSwitch::Custom {
+                    name: "passing".into(),
+                },
+            ]),
+            ..Default::default()
+        },
+        &flakes_nix(),
+        &mut Executer::new(false, &mut output),
+    );
+
+    assert!(matches!(result, Err(Errors::ActivationFailed { targets }) if &*targets == "failing"));
+    let output = String::from_utf8(output).expect("Output contained non-utf8 chars.");
+    assert!(output.starts_with("failing: activation failed: "));
+    assert!(output.ends_with("passed\n"));
+}
+
+#[test]
+fn resume_failed_switch() {
+    let path = std::env::temp_dir().join(format!("system-manager-resume-{}", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let history = History::new(&path);
+
+    let config = Config {
+        identity: "test_identity".into(),
+        nix_path: Utf8Path::new("/path/to/flake.nix").into(),
+        ..Default::default()
+    };
+    let options = Switch {
+        resume: true,
+        ..Default::default()
+    };
+
+    let record = |tree: Option<&str>, outcome| SwitchRecord {
+        time: chrono::Local::now(),
+        targets: Box::new(["system".into(), "home".into()]),
+        identity: "test_identity".into(),
+        flake_path: "/path/to/flake.nix".into(),
+        commit: None,
+        dirty: None,
+        lock_hash: None,
+        tree: tree.map(Into::into),
+        update: true,
+        offline: true,
+        system_generation: None,
+        home_generation: None,
+        durations: ["update", "system"]
+            .map(|stage| StageDuration {
+                stage: stage.into(),
+                seconds: 1.0,
+            })
+            .into(),
+        outcome,
+    };
+
+    history.append(&record(None, Outcome::Success)).unwrap();
+    assert!(matches!(
+        history::resume(&config, &options, &history),
+        Err(Errors::NothingToResume { .. })
+    ));
+
+    history.append(&record(None, Outcome::Failure)).unwrap();
+    let resumed = history::resume(&config, &options, &history).expect("Last switch failed.");
+    assert!(!resumed.update);
+    let targets: Vec<_> = resumed.targets.iter().map(ToSwitch::name).collect();
+    assert_eq!(targets, ["home"]);
+
+    // The nix configuration changed since the failure.
+    history
+        .append(&record(Some("0123abc"), Outcome::Failure))
+        .unwrap();
+    assert!(matches!(
+        history::resume(&config, &options, &history),
+        Err(Errors::ResumeChanged)
+    ));
+    let forced = Switch {
+        ignore_changes: true,
+        ..options.clone()
+    };
+    assert!(history::resume(&config, &forced, &history).is_ok());
+
+    // A system switch that didn't complete is resumed as it was given.
+    history
+        .append(&SwitchRecord {
+            durations: Box::new([]),
+            ..record(None, Outcome::Failure)
+        })
+        .unwrap();
+    let resumed = history::resume(&config, &options, &history).expect("Last switch failed.");
+    assert!(resumed.update);
+    assert!(matches!(
+        &resumed.targets[..],
+        [ToSwitch::System { offline: true }, ToSwitch::Home]
+    ));
+
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn remote_deploy() {
+    let config = Config {
+        identity: "test_identity".into(),
+        nix_path: Utf8Path::new("/path/to/flake.nix").into(),
+        hosts: [(
+            "web".into(),
+            Host {
+                target_host: "deploy@web.example.com".into(),
+                build_host: Some("builder".into()),
+                use_remote_sudo: true,
+            },
+        )]
+        .into(),
+        ..Default::default()
+    };
+
+    let (deployed, options) = config.deployment("web").expect("Host is configured.");
+    assert_eq!(&*deployed.identity, "web");
+
+    let mut output = Vec::new();
+    switch(
+        &deployed,
+        &options,
+        &flakes_nix(),
+        &mut Executer::new(true, &mut output),
+    )
+    .expect("Unable to run test commands.");
+
+    // Remote systems don't need local sudo.
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "nixos-rebuild --option extra-experimental-features pipe-operators switch --flake /path/to/flake.nix#web --target-host deploy@web.example.com --build-host builder --use-remote-sudo\n"
+    );
+
+    assert!(matches!(
+        config.deployment("db"),
+        Err(Errors::UnknownHost { .. })
+    ));
+
+    let home = Switch {
+        targets: Box::new([ToSwitch::Home]),
+        target_host: Some("deploy@web.example.com".into()),
+        ..Default::default()
+    };
+    let result = switch(
+        &config,
+        &home,
+        &flakes_nix(),
+        &mut Executer::new(true, Vec::new()),
+    );
+    assert!(matches!(result, Err(Errors::RemoteTarget)));
+}
+
+#[test]
+fn fleet_deploy() {
+    let host = |address: &str, build_host: Option<&str>| Host {
+        target_host: address.into(),
+        build_host: build_host.map(Into::into),
+        use_remote_sudo: false,
+    };
+    let config = Config {
+        identity: "test_identity".into(),
+        nix_path: Utf8Path::new("/path/to/flake.nix").into(),
+        hosts: [
+            ("web".into(), host("root@web", None)),
+            ("db".into(), host("root@db", Some("builder"))),
+            ("cache".into(), host("root@cache", None)),
+        ]
+        .into(),
+        groups: [("servers".into(), Box::from(["web".into(), "db".into()]))].into(),
+        ..Default::default()
+    };
+    let fleet = Fleet {
+        members: Box::new(["servers".into(), "web".into(), "cache".into()]),
+        jobs: 2,
+        canary: true,
+        fail_fast: false,
+    };
+
+    let mut output = Vec::new();
+    let report = fleet::deploy(
+        &config,
+        &fleet,
+        &flakes_nix(),
+        &mut Executer::new(true, &mut output),
+        None,
+    )
+    .expect("Unable to run test commands.");
+
+    let binding = String::from_utf8(output).expect("Output contained non-utf8 chars.");
+    let outputs: Vec<_> = binding.lines().collect();
+    assert_eq!(
+        outputs,
+        [
+            // The canary is activated before the rest are built.
+            "nix --extra-experimental-features pipe-operators build --no-link --print-out-paths /path/to/flake.nix#nixosConfigurations.web.config.system.build.toplevel",
+            "nixos-rebuild --option extra-experimental-features pipe-operators switch --flake /path/to/flake.nix#web --target-host root@web",
+            // The database is built on its build host while activating.
+            "nix --extra-experimental-features pipe-operators build --no-link --print-out-paths /path/to/flake.nix#nixosConfigurations.cache.config.system.build.toplevel",
+            "nixos-rebuild --option extra-experimental-features pipe-operators switch --flake /path/to/flake.nix#db --target-host root@db --build-host builder",
+            "nixos-rebuild --option extra-experimental-features pipe-operators switch --flake /path/to/flake.nix#cache --target-host root@cache",
+        ]
+    );
+
+    assert_eq!(
+        report.to_string(),
+        "Fleet summary:\n  IDENTITY  HOST        BUILD     ACTIVATE\n  web       root@web    done      done\n  db        root@db     deferred  done\n  cache     root@cache  done      done\n"
+    );
+    assert!(report.into_result().is_ok());
+
+    let unknown = Fleet {
+        members: Box::new(["mail".into()]),
+        ..fleet
+    };
+    let result = fleet::deploy(
+        &config,
+        &unknown,
+        &flakes_nix(),
+        &mut Executer::new(true, Vec::new()),
+        None,
+    );
+    assert!(matches!(result, Err(Errors::UnknownHost { .. })));
+
+    // The flake doesn't exist, so every local build fails with the errors it printed.
+    let missing = Config {
+        nix_path: Utf8Path::new("/nonexistent/flake.nix").into(),
+        ..config
+    };
+    let canary = Fleet {
+        members: Box::new(["web".into(), "cache".into()]),
+        ..fleet
+    };
+    let report = fleet::deploy(
+        &missing,
+        &canary,
+        &flakes_nix(),
+        &mut Executer::new(false, Vec::new()),
+        None,
+    )
+    .expect("Unable to run test commands.");
+    let stages: Vec<_> = report
+        .members
+        .iter()
+        .map(|member| (&member.build, &member.activation))
+        .collect();
+    assert!(matches!(
+        &stages[..],
+        [
+            (Stage::Failed(err), Stage::Skipped),
+            (Stage::Skipped, Stage::Skipped)
+        ] if err.starts_with("Command failed. Command: nix") && err.lines().count() > 1
+    ));
+
+    let report = fleet::deploy(
+        &missing,
+        &Fleet {
+            canary: false,
+            ..canary
+        },
+        &flakes_nix(),
+        &mut Executer::new(false, Vec::new()),
+        None,
+    )
+    .expect("Unable to run test commands.");
+    assert!(
+        report
+            .members
+            .iter()
+            .all(|member| matches!(member.build, Stage::Failed(_)))
+    );
+}
+
+#[test]
+fn check_all_report() {
+    let configurations = Configurations::parse(r#"{"home":["alice"],"nixos":["laptop","server"]}"#)
+        .expect("Configurations should parse.");
+    assert_eq!(&*configurations.nixos, ["laptop".into(), "server".into()]);
+    assert_eq!(&*configurations.home, ["alice".into()]);
+
+    let check = |category, name: &str, error: Option<&str>| Check {
+        category,
+        name: name.into(),
+        passed: error.is_none(),
+        error: error.map(Into::into),
+        log: error
+            .map(|_| Box::from(["error: <builder> & \"hello\" failed".into()]))
+            .unwrap_or_default(),
+        seconds: 1.5,
+    };
+    let report = check::Report {
+        checks: vec![
+            check("nixosConfigurations", "laptop", None),
+            check(
+                "homeConfigurations",
+                "alice",
+                Some("Command failed. Command: nix build \"<attr>\""),
+            ),
+        ],
+    };
+
+    assert_eq!(
+        report.format(Format::Junit).unwrap(),
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<testsuite name="system-manager check-all" tests="2" failures="1" time="3.000">
+  <testcase classname="nixosConfigurations" name="laptop" time="1.500"/>
+  <testcase classname="homeConfigurations" name="alice" time="1.500">
+    <failure message="Command failed. Command: nix build &quot;&lt;attr&gt;&quot;">error: &lt;builder&gt; &amp; &quot;hello&quot; failed</failure>
+  </testcase>
+</testsuite>
+"#
+    );
+
+    let json: serde_json::Value =
+        serde_json::from_str(&report.format(Format::Json).unwrap()).unwrap();
+    assert_eq!(json["checks"][1]["passed"], false);
+
+    assert!(matches!(
+        report.into_result(),
+        Err(Errors::ChecksFailed { checks }) if &*checks == "homeConfigurations.alice"
+    ));
+
+    // Nix's colours & other control characters are dropped from the XML.
+    let coloured = Check::new(
+        "flake",
+        "check",
+        Err(Errors::ChecksFailed {
+            checks: "bell\x07".into(),
+        }),
+        &["\x1b[31;1merror:\x1b[0m builder failed".into()],
+        Duration::from_secs(1),
+    );
+    assert_eq!(&*coloured.log, ["error: builder failed".into()]);
+    let junit = check::Report {
+        checks: vec![coloured],
+    }
+    .format(Format::Junit)
+    .unwrap();
+    assert!(!junit.contains(|c: char| c.is_control() && c != '\n'));
+    assert!(junit.contains(">error: builder failed</failure>"));
+}
+
+#[test]
+fn preflight() {
+    let mut output = Vec::new();
+
+    switch(
+        &Config {
+            identity: "test_identity".into(),
+            nix_path: Utf8Path::new("/path/to/flake.nix").into(),
+            preflight: true,
+            ..Default::default()
+        },
+        &Switch {
+            targets: Box::new([ToSwitch::System { offline: false }, ToSwitch::Home]),
+            ..Default::default()
+        },
+        &flakes_nix(),
+        &mut Executer::new(true, &mut output),
+    )
+    .expect("Unable to run test commands.");
+
+    let binding = String::from_utf8(output).expect("Output contained non-utf8 chars.");
+    let outputs: Vec<_> = binding.lines().take(4).collect();
+
+    // Evaluation happens before sudo is requested.
+    assert_eq!(
+        outputs,
+        [
+            "nix --extra-experimental-features pipe-operators flake check --no-build /path/to/flake.nix",
+            "nix --extra-experimental-features pipe-operators eval --raw /path/to/flake.nix#nixosConfigurations.test_identity.config.system.build.toplevel.drvPath",
+            "nix --extra-experimental-features pipe-operators eval --raw /path/to/flake.nix#homeConfigurations.test_identity.activationPackage.drvPath",
+            "echo 'Sudo perms required for system rebuild.'",
+        ]
+    );
+}
+
+#[test]
+fn nix_log_progress() {
+    let mut state = LogState::default();
+    let lines = [
+        r#"@nix {"action":"start","id":1,"level":0,"type":104,"text":"","fields":[]}"#,
+        r#"@nix {"action":"result","id":1,"type":105,"fields":[0,3,0,0]}"#,
+        r#"@nix {"action":"start","id":2,"level":3,"type":108,"text":"copying path","fields":["/nix/store/abc-glibc-2.40","https://cache.nixos.org"]}"#,
+        r#"@nix {"action":"stop","id":2}"#,
+        r#"@nix {"action":"start","id":3,"level":3,"type":105,"text":"building","fields":["/nix/store/def-hello-2.12.drv","",1,1]}"#,
+        r#"@nix {"action":"result","id":3,"type":104,"fields":["buildPhase"]}"#,
+        r#"@nix {"action":"result","id":3,"type":101,"fields":["gcc -o hello hello.c"]}"#,
+        r#"@nix {"action":"msg","level":0,"msg":"error: builder failed"}"#,
+        r#"@nix {"action":"msg","level":5,"msg":"evaluating file"}"#,
+        "plain output",
+    ];
+
+    let changed: Vec<_> = lines.iter().map(|line| state.handle(line)).collect();
+
+    assert_eq!(
+        changed,
+        [
+            false, true, true, true, true, true, false, false, false, false
+        ]
+    );
+    assert_eq!(
+        state.summary(),
+        "built 0/3, downloaded 1/1, hello-2.12 (buildPhase)"
+    );
+    assert_eq!(
+        state.log(),
+        [
+            "gcc -o hello hello.c".into(),
+            "error: builder failed".into(),
+            "plain output".into(),
+        ]
+    );
+
+    // When displaying, nix commands are shown as they are.
+    let mut output = Vec::new();
+    Executer::new(true, &mut output)
+        .with_progress(true)
+        .execute_nix("nix build")
+        .expect("Unable to run test commands.");
+    assert_eq!(output, b"nix build\n");
+
+    // The whole log is read, even past lines that aren't valid UTF-8.
+    let mut executer = Executer::new(false, std::io::sink()).with_progress(true);
+    assert_eq!(
+        executer.log_args(&flakes_nix()),
+        " --log-format internal-json -v"
+    );
+    assert!(matches!(
+        executer.execute_nix("printf 'bad \\377\\n' >&2; seq 100000 >&2; false"),
+        Err(CommandError::Failed { .. })
+    ));
+    assert_eq!(executer.log().len(), 100_001);
+    assert_eq!(&*executer.log()[0], "bad \u{fffd}");
+    assert_eq!(&*executer.log()[100_000], "100000");
+}
+
+#[test]
+fn nix_log_copy() {
+    let mut executer = Executer::new(false, std::io::sink());
+    assert_eq!(
+        executer.log_args(&NixInfo::new(Some(Version(2, 24, 0)), Box::new([]))),
+        ""
+    );
+
+    // Without progress, nix writes to the terminal itself & a copy of its log to a file.
+    let args = executer.log_args(&flakes_nix());
+    let path = args
+        .strip_prefix(" --option json-log-path ")
+        .expect("Recent nix versions copy their log.");
+    let event = r#"{"action":"msg","level":0,"msg":"error: builder failed"}"#;
+    assert!(matches!(
+        executer.execute_nix(&format!(
+            "echo '{event}' > {path}; echo '{{}}' >> {path}; false"
+        )),
+        Err(CommandError::Failed { .. })
+    ));
+    assert_eq!(
+        executer.log(),
+        ["error: builder failed".into(), "@nix {}".into()]
+    );
+    assert!(!std::path::Path::new(path).exists());
+
+    // The output of built paths is returned, while a quiet executer only keeps the log.
+    let mut quiet = Executer::new(false, std::io::sink()).with_quiet(true);
+    assert_eq!(
+        quiet
+            .capture_nix("echo /nix/store/abc-hello; echo building >&2")
+            .expect("The command succeeds."),
+        "/nix/store/abc-hello"
+    );
+    assert_eq!(quiet.log(), ["building".into()]);
+    assert!(quiet.capture_nix("echo failed >&2; false").is_err());
+    assert_eq!(quiet.log(), ["failed".into()]);
+
+    // The copy of a previous command isn't kept.
+    assert!(executer.execute_nix("true").is_ok());
+    assert!(executer.log().is_empty());
+
+    // Commands that can't copy their log still keep the errors they show.
+    assert!(executer.execute_nix("echo failed >&2; false").is_err());
+    assert_eq!(executer.log(), ["failed".into()]);
+    assert!(
+        Executer::new(true, Vec::new())
+            .log_args(&flakes_nix())
+            .is_empty()
+    );
+}
+
+#[test]
+fn nom_exit_status() {
+    let path = std::env::temp_dir().join(format!("system-manager-monitor-{}", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let line = r#"@nix {"action":"msg","level":0,"msg":"error: builder failed"}"#;
+
+    // The monitor is given the log, while the exit status is still the command's.
+    let monitor = format!("cat > {}", path.display());
+    let mut executer = Executer::new(false, std::io::sink()).with_monitor(&monitor);
+    assert_eq!(
+        executer.log_args(&flakes_nix()),
+        " --log-format internal-json -v"
+    );
+    assert!(matches!(
+        executer.execute_nix(&format!("echo '{line}' >&2; exit 3")),
+        Err(CommandError::Failed { .. })
+    ));
+    assert_eq!(
+        std::fs::read_to_string(&path).expect("The monitor should write the log."),
+        format!("{line}\n")
+    );
+    assert_eq!(executer.log(), ["error: builder failed".into()]);
+
+    // A monitor exiting early or failing doesn't change the outcome, nor stop the log being kept.
+    let mut executer = Executer::new(false, std::io::sink()).with_monitor("false");
+    assert!(executer.execute_nix("seq 100000 >&2; true").is_ok());
+    assert_eq!(executer.log().len(), 100_000);
+
+    // Whether or not nix-output-monitor is installed, the command's exit status is kept.
+    let mut executer = Executer::new(false, std::io::sink()).with_nom(true);
+    assert!(executer.execute_nix("true").is_ok());
+    assert!(matches!(
+        executer.execute_nix("false"),
+        Err(CommandError::Failed { command }) if &*command == "false"
+    ));
+
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn nix_failure_classification() {
+    let classify = |log: &[&str]| {
+        let log: Vec<Box<str>> = log.iter().map(|&line| line.into()).collect();
+        nix_log::classify(&log, "test_identity")
+    };
+
+    assert!(matches!(
+        classify(&[
+            "error:",
+            "       … while calling the 'derivationStrict' builtin",
+            "         at /builtin/derivation.nix:9:12: (source not available)",
+            "       \x1b[31;1merror:\x1b[0m undefined variable 'pkgs'",
+            "       at /nix/store/abc-source/hosts/desktop.nix:12:5:",
+            "           12|   pkgs.hello",
+        ]),
+        Some(Errors::EvaluationFailed { location, message })
+            if &*location == "hosts/desktop.nix:12:5" && &*message == "undefined variable 'pkgs'"
+    ));
+    assert!(matches!(
+        classify(&[
+            "error: builder for '/nix/store/abc-hello-2.12.drv' failed with exit code 1",
+            "error: 1 dependencies of derivation '/nix/store/def-system.drv' failed to build",
+        ]),
+        Some(Errors::BuildFailed { derivation, log: None }) if &*derivation == "/nix/store/abc-hello-2.12.drv"
+    ));
+    assert!(matches!(
+        classify(&["error: unable to download 'https://cache.nixos.org/abc.narinfo': Couldn't resolve host name (6)"]),
+        Some(Errors::DownloadFailed { detail })
+            if detail.starts_with("unable to download 'https://cache.nixos.org/abc.narinfo'")
+    ));
+    assert!(matches!(
+        classify(&[
+            "Existing file '/home/test/.bashrc' is in the way of '/nix/store/abc-home-manager-files/.bashrc'",
+        ]),
+        Some(Errors::ClobberedFile { path }) if &*path == "/home/test/.bashrc"
+    ));
+    assert!(matches!(
+        classify(&[
+            "error: flake 'git+file:///path/to/flake' does not provide attribute 'packages.x86_64-linux.nixosConfigurations.test_identity.config.system.build.toplevel' or 'nixosConfigurations.test_identity.config.system.build.toplevel'",
+        ]),
+        Some(Errors::MissingAttribute { attribute, identity })
+            if &*attribute == "nixosConfigurations.test_identity.config.system.build.toplevel"
+                && &*identity == "test_identity"
+    ));
+    assert!(matches!(
+        classify(&[
+            "error: Path 'hosts/laptop.nix' in the repository \"/path/to/flake\" is not tracked by Git.",
+        ]),
+        Some(Errors::GitTreeNotClean { detail }) if detail.starts_with("Path 'hosts/laptop.nix'")
+    ));
+    assert!(classify(&["error: something unexpected"]).is_none());
+
+    // The log of failed commands is kept from the copy nix writes, while nix still shows it.
+    let mut executer = Executer::new(false, std::io::sink());
+    let event = r#"{"action":"msg","level":0,"msg":"error: builder for '/nix/store/abc-hello.drv' failed"}"#;
+    let err = executer
+        .execute_nix(&format!(
+            "nix(){{ shift 2; echo {} > \"$1\"; false; }}; nix{}",
+            command_builder::quote(event),
+            executer.log_args(&flakes_nix())
+        ))
+        .expect_err("The command fails.");
+    assert!(matches!(
+        nix_log::failure(err, executer.log(), "test_identity"),
+        Errors::BuildFailed { derivation, .. } if &*derivation == "/nix/store/abc-hello.drv"
+    ));
+}
+
+#[test]
+fn home_manager_failure() {
+    // Home-manager isn't given nix's logging flags, so its errors are kept as it shows them.
+    let bin = TempDir::new("home-manager-failure");
+    let stub = bin.join("home-manager");
+    std::fs::write(
+        &stub,
+        "#!/bin/sh\necho \"Existing file '/home/test/.bashrc' is in the way of '/nix/store/abc-home-manager-files/.bashrc'\" >&2\nexit 1\n",
+    )
+    .unwrap();
+    std::fs::set_permissions(&stub, std::os::unix::fs::PermissionsExt::from_mode(0o755)).unwrap();
+    let path = std::env::var_os("PATH").unwrap_or_default();
+    let mut paths = vec![bin.to_path_buf()];
+    paths.extend(std::env::split_paths(&path));
+    // SAFETY: The stub only comes first, so other tests still find the same commands.
+    unsafe { std::env::set_var("PATH", std::env::join_paths(paths).unwrap()) };
+
+    let result = switch(
+        &Config {
+            identity: "test_identity".into(),
+            nix_path: Utf8Path::new("/path/to/flake.nix").into(),
+            ..Default::default()
+        },
+        &Switch {
+            targets: Box::new([ToSwitch::Home]),
+            ..Default::default()
+        },
+        &flakes_nix(),
+        &mut Executer::new(false, std::io::sink()),
+    );
+    unsafe { std::env::set_var("PATH", path) };
+
+    assert!(matches!(
+        result,
+        Err(Errors::ClobberedFile { path }) if &*path == "/home/test/.bashrc"
+    ));
+}
+
+#[test]
+fn failed_build_log() {
+    let derivation = "/nix/store/abc-hello-2.12.drv";
+    let failure = || Errors::BuildFailed {
+        derivation: derivation.into(),
+        log: None,
+    };
+    let directory =
+        std::env::temp_dir().join(format!("system-manager-build-logs-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&directory);
+
+    let log = (1..=30)
+        .map(|line| format!("line {line}"))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let tail = nix_log::build_log_tail(derivation, &log, 3);
+    assert_eq!(
+        tail,
+        "Last 3 lines of the build log of '/nix/store/abc-hello-2.12.drv':\nline 28\nline 29\nline 30"
+    );
+    assert_eq!(
+        nix_log::build_log_tail(derivation, "only line", 25),
+        "Last 1 lines of the build log of '/nix/store/abc-hello-2.12.drv':\nonly line"
+    );
+
+    let path = nix_log::save_build_log(derivation, &log, &directory).expect("The log is saved.");
+    assert_eq!(path, directory.join("abc-hello-2.12.log"));
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), log + "\n");
+
+    // The hint points to the saved log, if there is one.
+    let saved = Errors::BuildFailed {
+        derivation: derivation.into(),
+        log: Some(path.clone().into_boxed_path()),
+    };
+    assert_eq!(
+        saved.to_string(),
+        format!(
+            "Building '/nix/store/abc-hello-2.12.drv' failed.\nHint: the full build log is saved to '{}'.",
+            path.display()
+        )
+    );
+    assert_eq!(
+        failure().to_string(),
+        "Building '/nix/store/abc-hello-2.12.drv' failed.\nHint: run 'nix log /nix/store/abc-hello-2.12.drv' to see why."
+    );
+    std::fs::remove_dir_all(&directory).unwrap();
+
+    // Nothing is saved when only displaying the commands.
+    let mut output = Vec::new();
+    let err = nix_log::show_build_log(
+        failure(),
+        25,
+        Some(&directory),
+        &flakes_nix(),
+        &mut Executer::new(true, &mut output),
+    );
+    assert!(matches!(err, Errors::BuildFailed { log: None, .. }));
+    assert_eq!(
+        String::from_utf8(output).expect("Output contained non-utf8 chars."),
+        "nix --extra-experimental-features pipe-operators log /nix/store/abc-hello-2.12.drv\n"
+    );
+    assert!(!directory.exists());
+}
+
+#[test]
+fn custom_target_arguments() {
+    let parse = |args: &[&str]| match options::try_parse_from(
+        ["system-manager", "switch"].iter().chain(args),
+    ) {
+        Ok(Task::Command {
+            option: Operation::Switch { switch },
+        }) => Ok(switch),
+        Ok(_) => panic!("Expected a switch."),
+        Err(err) => Err(err.kind()),
+    };
+
+    let switch = parse(&["--update", "droid", "--show-trace", "--", "-L"]).unwrap();
+    assert!(switch.update);
+    assert!(matches!(&switch.targets[..], [ToSwitch::Custom { name }] if &**name == "droid"));
+    assert_eq!(
+        &*switch.extra_args,
+        [Box::from("--show-trace"), "-L".into()]
+    );
+
+    // Switch flags after the target name aren't silently passed through.
+    for flag in ["--update", "--display", "-y"] {
+        assert_eq!(
+            parse(&["droid", flag]).err(),
+            Some(ErrorKind::ArgumentConflict)
+        );
+    }
+    assert!(parse(&["droid", "--", "--update"]).is_ok());
+
+    let config = std::env::temp_dir().join(format!("reserved-target-{}.json", std::process::id()));
+    std::fs::write(
+        &config,
+        r#"{ "identity": "test", "nix_path": "/flake", "targets": { "home": { "command": "true" } } }"#,
+    )
+    .unwrap();
+    let result = Config::parse(&config);
+    std::fs::remove_file(&config).unwrap();
+    assert!(matches!(result, Err(Errors::ReservedTarget { name }) if &*name == "home"));
+}
@@ -0,0 +1,220 @@
+use crate::{
+    Config, Errors,
+    command_builder::{Execute as _, Executer, quote},
+    history::{History, recorded_switch},
+    hooks::{self, HookContext},
+    nix::NixInfo,
+    options::{Switch, ToSwitch},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::Display,
+    time::{Duration, Instant},
+};
+
+/// A single step of a workflow.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "step", rename_all = "kebab-case")]
+pub enum Step {
+    /// Update the 'flake.lock' file.
+    Update,
+    /// Switch the given targets, in order.
+    ///
+    /// The target names are the same as those accepted by the switch sub command, though "all"
+    /// can't be given with other names.
+    Switch { targets: Box<[Box<str>]> },
+    /// Delete unreachable paths from the nix store.
+    Gc {
+        /// Also delete generations older than the given period, such as "14d".
+        #[serde(default)]
+        delete_older_than: Option<Box<str>>,
+        /// Run with sudo, so system generations can be deleted.
+        #[serde(default)]
+        privileged: bool,
+    },
+    /// Run a shell command, with the same environment variables as hooks.
+    Hook { command: Box<str> },
+    /// Commit the given files in the nix configuration, if they have changed.
+    Commit {
+        message: Box<str>,
+        #[serde(default = "default_commit_paths")]
+        paths: Box<[Box<str>]>,
+    },
+}
+
+/// The files committed by a commit step if none are configured.
+fn default_commit_paths() -> Box<[Box<str>]> {
+    Box::new(["flake.lock".into()])
+}
+
+impl Display for Step {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Step::Update => write!(f, "update"),
+            Step::Switch { targets } => write!(f, "switch {}", targets.join(" ")),
+            Step::Gc { .. } => write!(f, "gc"),
+            Step::Hook { command } => write!(f, "hook '{command}'"),
+            Step::Commit { message, .. } => write!(f, "commit '{message}'"),
+        }
+    }
+}
+
+impl Step {
+    /// Executes the step, recording any switches in the history if given.
+    fn run<T: std::io::Write>(
+        &self,
+        config: &Config,
+        nix: &NixInfo,
+        executer: &mut Executer<T>,
+        history: Option<&History>,
+    ) -> Result<(), Errors> {
+        let path = &config.nix_path;
+
+        match self {
+            Step::Update => crate::update(config, nix, executer),
+            Step::Switch { targets } => {
+                let mut options = Switch::default();
+                if targets.iter().any(|target| &**target == "all") {
+                    if targets.len() > 1 {
+                        return Err(Errors::MixedAllTarget);
+                    }
+                    options.all = true;
+                } else {
+                    options.targets = targets
+                        .iter()
+                        .flat_map(|target| match &**target {
+                            "both" => vec![ToSwitch::System { offline: false }, ToSwitch::Home],
+                            name => vec![ToSwitch::from_name(name)],
+                        })
+                        .collect();
+                }
+                match history {
+                    Some(history) => recorded_switch(config, &options, nix, executer, history),
+                    None => crate::switch(config, &options, nix, executer),
+                }
+            }
+            Step::Gc {
+                delete_older_than,
+                privileged,
+            } => {
+                let sudo = if *privileged { "sudo " } else { "" };
+                let older_than = delete_older_than
+                    .as_ref()
+                    .map(|period| format!(" --delete-older-than {}", quote(period)))
+                    .unwrap_or_default();
+                Ok(executer.execute(&format!("{sudo}nix-collect-garbage{older_than}"))?)
+            }
+            Step::Hook { command } => {
+                let context = HookContext {
+                    identity: &config.identity,
+                    path,
+                    target: None,
+                    outcome: None,
+                };
+                Ok(hooks::run(
+                    std::slice::from_ref(command),
+                    &context,
+                    executer,
+                )?)
+            }
+            Step::Commit { message, paths } => {
+                let path = quote(path.as_str());
+                let paths = paths
+                    .iter()
+                    .map(|path| quote(path))
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                Ok(executer.execute(&format!(
+                    "git -C {path} diff --quiet HEAD -- {paths} || git -C {path} commit -m {} -- {paths}",
+                    quote(message)
+                ))?)
+            }
+        }
+    }
+}
+
+/// The outcome of running a workflow.
+pub struct Report {
+    /// The name of the workflow.
+    name: Box<str>,
+    /// The steps that completed, with how long they took.
+    completed: Vec<(String, Duration)>,
+    /// The step that failed, with how long it took & the error.
+    failed: Option<(String, Duration, Errors)>,
+    /// The steps that were not run due to the failure.
+    skipped: Vec<String>,
+}
+
+impl Report {
+    /// Converts the report into the error of the failed step, if any.
+    pub fn into_result(self) -> Result<(), Errors> {
+        match self.failed {
+            Some((_, _, err)) => Err(err),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Workflow '{}' summary:", self.name)?;
+        for (step, duration) in &self.completed {
+            writeln!(f, "  done    {step} ({})", format_duration(*duration))?;
+        }
+        if let Some((step, duration, _)) = &self.failed {
+            writeln!(f, "  failed  {step} ({})", format_duration(*duration))?;
+        }
+        for step in &self.skipped {
+            writeln!(f, "  skipped {step}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Runs each step of the named workflow in order, stopping at the first failure.
+///
+/// Any switches are recorded in the history if given.
+pub fn run<T: std::io::Write>(
+    config: &Config,
+    name: &str,
+    nix: &NixInfo,
+    executer: &mut Executer<T>,
+    history: Option<&History>,
+) -> Result<Report, Errors> {
+    let steps = config
+        .workflows
+        .get(name)
+        .ok_or_else(|| Errors::UnknownWorkflow { name: name.into() })?;
+
+    let mut report = Report {
+        name: name.into(),
+        completed: Vec::new(),
+        failed: None,
+        skipped: Vec::new(),
+    };
+
+    let mut steps = steps.iter();
+    for step in steps.by_ref() {
+        let start = Instant::now();
+        match step.run(config, nix, executer, history) {
+            Ok(()) => report.completed.push((step.to_string(), start.elapsed())),
+            Err(err) => {
+                report.failed = Some((step.to_string(), start.elapsed(), err));
+                break;
+            }
+        }
+    }
+    report.skipped = steps.map(ToString::to_string).collect();
+
+    Ok(report)
+}
+
+/// Formats the duration in a human-readable form, such as "2m 5s".
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs >= 60 {
+        format!("{}m {}s", secs / 60, secs % 60)
+    } else {
+        format!("{:.1}s", duration.as_secs_f32())
+    }
+}